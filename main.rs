@@ -1,14 +1,17 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::Result;
 
 use log::{Level, LevelFilter, Log};
-use tokio::net::TcpListener;
 use wfblmark::start_cleanup_task;
 use wfcipu::parsers::ip::supports_ipv6;
-use wfconfig::{aux_config::AuxConfig, core_launch_task, parse_args};
+use wfconfig::{
+    aux_config::{AuxConfig, BindMode},
+    core_launch_task, parse_args, request_reload,
+};
+use wfcore::upstream::pool::start_health_checks;
 use wfdns::test_dns_servers;
-use wfsocks::socks5_proxy;
+use wfsocks::{bind_listener, drain_sessions, inherited_listeners, serve_listener, tun_proxy};
 use wftamper::service::compile_patterns;
 
 #[macro_use]
@@ -60,14 +63,60 @@ async fn spawn_hot_reloader() {
     warn!("Hot reloading is not available on dangerous targets");
 }
 
+/* SIGHUP reloads the config just like the file watcher does,
+ * SIGTERM and SIGINT stop accepting and start draining sessions
+ */
+#[cfg(unix)]
+async fn wait_for_shutdown() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    let mut sigint = signal(SignalKind::interrupt()).expect("Failed to listen for SIGINT");
+    let mut sighup = signal(SignalKind::hangup()).expect("Failed to listen for SIGHUP");
+
+    loop {
+        tokio::select! {
+            _ = sigterm.recv() => {
+                info!("Got SIGTERM, shutting down");
+
+                return;
+            }
+            _ = sigint.recv() => {
+                info!("Got SIGINT, shutting down");
+
+                return;
+            }
+            _ = sighup.recv() => {
+                info!("Got SIGHUP, reloading the config");
+
+                request_reload();
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_shutdown() {
+    if tokio::signal::ctrl_c().await.is_ok() {
+        info!("Got Ctrl-C, shutting down");
+    }
+}
+
 struct WaterfallLogger;
 
 impl Log for WaterfallLogger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
+    /* The file watcher traces every event it sees, which would feed on
+     * itself once the log is written next to a watched file
+     */
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() < Level::Trace || !metadata.target().starts_with("notify")
     }
 
     fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
         let color = match record.level() {
             Level::Error => "\x1b[31m",
             Level::Warn => "\x1b[33m",
@@ -107,6 +156,7 @@ async fn main() -> Result<()> {
 
     spawn_hot_reloader().await;
     start_cleanup_task();
+    start_health_checks();
 
     info!(
         "Waterfall is starting {} IPv6 support",
@@ -129,21 +179,6 @@ async fn main() -> Result<()> {
 
     debug!("Working with a config: {config:?}");
 
-    let listener: TcpListener = TcpListener::bind(
-        format!(
-            "{}:{}",
-            config.bind_options.bind_host, config.bind_options.bind_port
-        )
-        .replace("\"", "")
-        .replace("\"", ""),
-    )
-    .await?;
-
-    info!(
-        "Socks5 proxy bound at {}:{}",
-        config.bind_options.bind_host, config.bind_options.bind_port
-    );
-
     if config.bind_options.iface_ipv4 != "default".to_string() {
         info!(
             "OK! I'll bind every IPv4 socket to interface {}, just as you've said",
@@ -158,11 +193,42 @@ async fn main() -> Result<()> {
         );
     }
 
-    loop {
-        let (client, _) = listener.accept().await?;
+    let drain_timeout = Duration::from_millis(config.bind_options.drain_timeout);
 
-        tokio::spawn(async move {
-            let _ = socks5_proxy(client).await;
-        });
+    tokio::select! {
+        result = serve(config) => return result,
+        _ = wait_for_shutdown() => {}
     }
+
+    drain_sessions(drain_timeout).await;
+
+    Ok(())
+}
+
+async fn serve(config: AuxConfig) -> Result<()> {
+    if config.bind_options.bind_mode == BindMode::Tun {
+        return tun_proxy(config).await;
+    }
+
+    let bind_mode = config.bind_options.bind_mode.clone();
+
+    let mut bound = inherited_listeners()?;
+
+    if bound.is_empty() {
+        for options in config.bind_options.effective_listeners() {
+            bound.push(bind_listener(&options, &bind_mode).await?);
+        }
+    }
+
+    let mut listeners = tokio::task::JoinSet::new();
+
+    for bound in bound {
+        listeners.spawn(serve_listener(bound, bind_mode.clone()));
+    }
+
+    while let Some(result) = listeners.join_next().await {
+        result??;
+    }
+
+    Ok(())
 }
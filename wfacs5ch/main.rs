@@ -1,8 +1,8 @@
 use anyhow::Result;
-use log::{info, warn};
+use log::info;
 use std::time::{self, Duration};
-use wfconfig::parse_args;
 use wfconfig::{aux_config::AuxConfig, strategy::Strategies, NetworkProtocol};
+use wfconfig::{parse_args, with_config};
 use wfdesync::disoob::{Disoob, DisorderedOOB, Oob2};
 use wfdesync::disorder::{Disorder, Disorder2, DisorderD};
 use wfdesync::fake::{Fake, Fake2Disorder, FakeD, FakeInsert, FakeMD, FakeSurround, Meltdown};
@@ -52,24 +52,13 @@ async fn execute_l4_bypasses<'a>(
             continue;
         }
 
-        let filter_result = Whitelist::check_whitelist(
-            &Some(
-                (strategy.filter_sni.items)
-                    .iter()
-                    .map(|e| e.value.clone())
-                    .collect::<Vec<_>>(),
-            ),
+        let whitelisted = Whitelist::check_whitelist(
+            &strategy.filter_sni.matcher(),
             sni_data,
             current_data.as_slice(),
         );
 
-        if let Ok(result) = filter_result {
-            if !result {
-                continue;
-            }
-        } else {
-            warn!("Website blocked per filter rules");
-
+        if !whitelisted {
             continue;
         }
 
@@ -228,19 +217,41 @@ async fn execute_l7_bypasses(config: &AuxConfig) {
     }
 }
 
+async fn execute_bypasses(
+    socket: &mut tokio::net::TcpStream,
+    config: &AuxConfig,
+    data: &[u8],
+    sni_data: &(u32, u32),
+) -> Result<Vec<u8>> {
+    let mut l5_data = execute_l5_bypasses(data);
+
+    process_packet(&mut l5_data).await?;
+    execute_l4_bypasses(socket, config, &mut l5_data, sni_data).await?;
+    execute_l7_bypasses(config).await;
+
+    Ok(l5_data)
+}
+
 pub async fn client_hook<'a>(
     socket: &'a mut tokio::net::TcpStream,
     data: &'a [u8],
+    profile: Option<&str>,
 ) -> Result<Vec<u8>> {
     let config = parse_args();
 
     let sni_data = Sni::parse_sni_index(Vec::from(data));
 
-    let mut l5_data = execute_l5_bypasses(data);
-
-    process_packet(&mut l5_data).await?;
-    execute_l4_bypasses(socket, &config, &mut l5_data, &sni_data).await?;
-    execute_l7_bypasses(&config).await;
-
-    Ok(l5_data)
+    /* Strategies deep down read the config on their own, so a profile
+     * replaces it for the whole hook
+     */
+    match profile.and_then(|profile| config.with_profile(profile)) {
+        Some(config) => {
+            with_config(
+                config.clone(),
+                execute_bypasses(socket, &config, data, &sni_data),
+            )
+            .await
+        }
+        None => execute_bypasses(socket, &config, data, &sni_data).await,
+    }
 }
@@ -1,3 +1,5 @@
+use std::net::IpAddr;
+
 use anyhow::Result;
 use iprobe::ipv6;
 use log::warn;
@@ -10,6 +12,10 @@ pub struct IpParser {
     pub port: u16,
     pub dest_addr_type: u8,
     pub is_udp: bool,
+    /* Every address the resolver returned, in preference order, so the
+     * connection can fall back across them. Empty when host_raw came as is
+     */
+    pub resolved: Vec<IpAddr>,
 }
 
 impl IpParser {
@@ -29,6 +35,7 @@ impl IpParser {
                         host_unprocessed: vec![0, 0, 0, 0],
                         port: 0,
                         is_udp,
+                        resolved: vec![],
                     });
                 }
 
@@ -38,6 +45,7 @@ impl IpParser {
                     host_unprocessed: buffer[4..8].to_vec(),
                     port: u16::from_be_bytes([buffer[8], buffer[9]]),
                     is_udp,
+                    resolved: vec![],
                 })
             }
             3 => {
@@ -52,6 +60,7 @@ impl IpParser {
                         host_unprocessed: vec![0, 0, 0, 0],
                         port: 0,
                         is_udp,
+                        resolved: vec![],
                     });
                 }
 
@@ -65,6 +74,7 @@ impl IpParser {
                     host_unprocessed: domain.to_vec(),
                     port,
                     is_udp,
+                    resolved: vec![],
                 })
             }
             4 => {
@@ -75,6 +85,7 @@ impl IpParser {
                         host_unprocessed: vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
                         port: 0,
                         is_udp,
+                        resolved: vec![],
                     });
                 }
 
@@ -84,6 +95,7 @@ impl IpParser {
                     host_unprocessed: buffer[4..20].to_vec(),
                     port: u16::from_be_bytes([buffer[20], buffer[21]]),
                     is_udp,
+                    resolved: vec![],
                 })
             }
             _ => Ok(IpParser {
@@ -92,6 +104,7 @@ impl IpParser {
                 host_unprocessed: vec![0, 0, 0, 0],
                 port: 13437,
                 is_udp,
+                resolved: vec![],
             }),
         }
     }
@@ -1,8 +1,10 @@
 use crate::strategy::{FilterSniList, Strategy};
 use crate::weak_range::WeakRange;
+use ipnetwork::IpNetwork;
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
-#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq, Hash, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum WhiteListedSNI {
     Domain,
@@ -10,7 +12,7 @@ pub enum WhiteListedSNI {
     File,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
 pub struct WhiteListedSNIWrapper {
     #[serde(rename = "@list")]
     pub list: WhiteListedSNI,
@@ -18,9 +20,42 @@ pub struct WhiteListedSNIWrapper {
     pub value: String,
 }
 
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BindMode {
+    Proxy,
+    Redirect,
+    Tproxy,
+    Tun,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ListenerOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none", rename = "@tag")]
+    pub tag: Option<String>,
+    #[serde(default = "default_bind_host", rename = "@host")]
+    pub host: String,
+    #[serde(default = "default_bind_port", rename = "@port")]
+    pub port: u16,
+    #[serde(default, rename = "@dual-stack")]
+    pub dual_stack: bool,
+
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        rename = "@unix-path"
+    )]
+    pub unix_path: Option<String>,
+    #[serde(default = "default_unix_permissions", rename = "@unix-permissions")]
+    pub unix_permissions: String,
+}
+
 #[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct BindOptions {
+    #[serde(default = "default_bind_mode", rename = "@mode")]
+    pub bind_mode: BindMode,
     #[serde(default = "default_bind_host", rename = "@host")]
     pub bind_host: String,
     #[serde(default = "default_bind_port", rename = "@port")]
@@ -31,12 +66,20 @@ pub struct BindOptions {
     #[serde(default = "default_bind_iface", rename = "@iface-ipv6")]
     pub iface_ipv6: String,
 
+    #[serde(default = "default_bind_tun_name", rename = "@tun-name")]
+    pub bind_tun_name: String,
     #[serde(default = "default_bind_iface_mtu", rename = "@iface-mtu")]
     pub bind_iface_mtu: u32,
     #[serde(default = "default_bind_iface_ipv4", rename = "@iface-ipv4-ip")]
     pub bind_iface_ipv4: String,
     #[serde(default = "default_bind_iface_ipv6", rename = "@iface-ipv6-ip")]
     pub bind_iface_ipv6: String,
+
+    #[serde(default = "default_drain_timeout", rename = "@drain-timeout-ms")]
+    pub drain_timeout: u64,
+
+    #[serde(default = "default_listeners")]
+    pub listeners: Vec<ListenerOptions>,
 }
 
 #[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
@@ -92,6 +135,50 @@ pub struct SocketOptions {
 
     #[serde(default = "default_oob_streamhell_data", rename = "@oob-hell-data")]
     pub so_oob_streamhell_data: String,
+
+    /* Timeouts are in milliseconds, 0 disables the respective one */
+    #[serde(default = "default_connect_timeout", rename = "@connect-timeout-ms")]
+    pub connect_timeout: u64,
+    #[serde(
+        default = "default_handshake_timeout",
+        rename = "@handshake-timeout-ms"
+    )]
+    pub handshake_timeout: u64,
+    #[serde(default = "default_idle_timeout", rename = "@upload-idle-timeout-ms")]
+    pub upload_idle_timeout: u64,
+    #[serde(default = "default_idle_timeout", rename = "@download-idle-timeout-ms")]
+    pub download_idle_timeout: u64,
+
+    #[serde(
+        default = "default_keepalive_interval",
+        rename = "@keepalive-interval-ms"
+    )]
+    pub keepalive_interval: u64,
+    #[serde(default = "default_keepalive_count", rename = "@keepalive-count")]
+    pub keepalive_count: u32,
+
+    #[serde(default = "default_relay_mode", rename = "@relay-mode")]
+    pub relay_mode: RelayMode,
+}
+
+/* Threaded relays give every connection its own OS thread and forward each
+ * server TLS record as a separate write, which schannel and Go's TLS need
+ */
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RelayMode {
+    Async,
+    Threaded,
+}
+
+impl RelayMode {
+    pub fn from_name(name: &str) -> Option<RelayMode> {
+        match name {
+            "async" => Some(RelayMode::Async),
+            "threaded" => Some(RelayMode::Threaded),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
@@ -125,6 +212,12 @@ pub enum RouterRuleScope {
     DnsQuery,
     SNI,
     IP,
+    Port,
+    Protocol,
+    User,
+    Listener,
+    GeoIP,
+    ASN,
 }
 
 #[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
@@ -167,6 +260,140 @@ pub struct PatternOptions {
     pub patterns: Vec<PatternRule>,
 }
 
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+pub struct AuthUser {
+    #[serde(rename = "@username")]
+    pub username: String,
+    #[serde(rename = "@password")]
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct AuthOptions {
+    #[serde(default = "default_auth_users")]
+    pub users: Vec<AuthUser>,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PoolStrategy {
+    RoundRobin,
+    LeastConn,
+    Failover,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+pub struct UpstreamPoolMember {
+    #[serde(rename = "@exec")]
+    pub exec: String,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct UpstreamPool {
+    #[serde(rename = "@name")]
+    pub name: String,
+    #[serde(default = "default_pool_strategy", rename = "@strategy")]
+    pub strategy: PoolStrategy,
+    #[serde(default)]
+    pub members: Vec<UpstreamPoolMember>,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct UpstreamOptions {
+    #[serde(
+        default = "default_health_check_interval",
+        rename = "@health-check-interval-ms"
+    )]
+    /* 0 disables the health checks */
+    pub health_check_interval: u64,
+    #[serde(default = "default_upstream_pools")]
+    pub pools: Vec<UpstreamPool>,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccessAction {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+pub struct AccessRule {
+    #[serde(rename = "@action")]
+    pub action: AccessAction,
+    /* Parsed along with the config, a bad CIDR fails the load */
+    #[serde(rename = "@cidr")]
+    pub cidr: IpNetwork,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct AccessOptions {
+    #[serde(default = "default_access_action", rename = "@default")]
+    pub default_action: AccessAction,
+    #[serde(default, rename = "@max-connections-per-client")]
+    pub max_connections_per_client: usize,
+    #[serde(default = "default_access_rules")]
+    pub rules: Vec<AccessRule>,
+}
+
+/* Rates are in bytes per second and count both directions, 0 means unlimited */
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ShapingOptions {
+    #[serde(default, rename = "@global-rate")]
+    pub global_rate: u64,
+    #[serde(default, rename = "@client-rate")]
+    pub client_rate: u64,
+}
+
+/* Databases for the GeoIP and ASN router scopes, either MaxMind (.mmdb)
+ * files or CSV files of network,value lines
+ */
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct GeoIpOptions {
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        rename = "@country-database"
+    )]
+    pub country_database: Option<String>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        rename = "@asn-database"
+    )]
+    pub asn_database: Option<String>,
+}
+
+/* A desync chain picked by a `direct <profile>` router rule. Its strategies
+ * replace the global ones, the option sections replace their global
+ * counterparts when present
+ */
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct StrategyProfile {
+    #[serde(rename = "@name")]
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fake_packet_options: Option<FakePacketOptions>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub desync_options: Option<DesyncOptions>,
+    #[serde(default)]
+    pub strategies: Vec<Option<Strategy>>,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct StrategyProfiles {
+    #[serde(default = "default_strategy_profile_list")]
+    pub profiles: Vec<StrategyProfile>,
+}
+
 #[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct AuxConfig {
@@ -178,6 +405,18 @@ pub struct AuxConfig {
     pub dns_options: DnsOptions,
     pub router_options: RouterOptions,
     pub pattern_options: PatternOptions,
+    #[serde(default = "default_auth_options")]
+    pub auth_options: AuthOptions,
+    #[serde(default = "default_access_options")]
+    pub access_options: AccessOptions,
+    #[serde(default = "default_upstream_options")]
+    pub upstream_options: UpstreamOptions,
+    #[serde(default = "default_shaping_options")]
+    pub shaping_options: ShapingOptions,
+    #[serde(default = "default_strategy_profiles")]
+    pub strategy_profiles: StrategyProfiles,
+    #[serde(default = "default_geoip_options")]
+    pub geoip_options: GeoIpOptions,
 
     #[serde(default = "default_whitelist_sni")]
     pub whitelist_sni: bool,
@@ -206,17 +445,74 @@ pub struct DnsOptions {
     pub doh_servers: Vec<DohServer>,
 }
 
+impl SocketOptions {
+    /* A zero timeout never fires, tokio treats an overflowing deadline as a far future one */
+    pub fn timeout(millis: u64) -> Duration {
+        match millis {
+            0 => Duration::MAX,
+            millis => Duration::from_millis(millis),
+        }
+    }
+}
+
+impl AuxConfig {
+    /* The config a connection using the profile sees, None for an unknown profile */
+    pub fn with_profile(&self, name: &str) -> Option<AuxConfig> {
+        let profile = self
+            .strategy_profiles
+            .profiles
+            .iter()
+            .find(|profile| profile.name == name)?;
+
+        let mut config = self.clone();
+
+        config.strategies = profile.strategies.clone();
+
+        if let Some(ref fake_packet_options) = profile.fake_packet_options {
+            config.fake_packet_options = fake_packet_options.clone();
+        }
+
+        if let Some(ref desync_options) = profile.desync_options {
+            config.desync_options = desync_options.clone();
+        }
+
+        Some(config)
+    }
+}
+
+impl BindOptions {
+    /* An empty listener list means the single listener from host/port */
+    pub fn effective_listeners(&self) -> Vec<ListenerOptions> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+
+        vec![ListenerOptions {
+            tag: None,
+            host: self.bind_host.clone(),
+            port: self.bind_port,
+            dual_stack: false,
+            unix_path: None,
+            unix_permissions: default_unix_permissions(),
+        }]
+    }
+}
+
 impl Default for AuxConfig {
     fn default() -> Self {
         Self {
             bind_options: BindOptions {
+                bind_mode: default_bind_mode(),
                 bind_host: default_bind_host(),
                 bind_port: default_bind_port(),
                 iface_ipv4: default_bind_iface(),
                 iface_ipv6: default_bind_iface(),
+                bind_tun_name: default_bind_tun_name(),
                 bind_iface_mtu: default_bind_iface_mtu(),
                 bind_iface_ipv4: default_bind_iface_ipv4(),
                 bind_iface_ipv6: default_bind_iface_ipv6(),
+                drain_timeout: default_drain_timeout(),
+                listeners: default_listeners(),
             },
             dns_options: DnsOptions {
                 integrated_doh_enabled: default_integrated_doh_enabled(),
@@ -258,6 +554,13 @@ impl Default for AuxConfig {
                 so_l7_packet_jitter_max: default_l7_packet_jitter_max(),
                 so_disable_sack: false,
                 so_oob_streamhell_data: default_oob_streamhell_data(),
+                connect_timeout: default_connect_timeout(),
+                handshake_timeout: default_handshake_timeout(),
+                upload_idle_timeout: default_idle_timeout(),
+                download_idle_timeout: default_idle_timeout(),
+                keepalive_interval: default_keepalive_interval(),
+                keepalive_count: default_keepalive_count(),
+                relay_mode: default_relay_mode(),
             },
             router_options: RouterOptions {
                 rules: vec![RouterRule {
@@ -285,6 +588,12 @@ impl Default for AuxConfig {
                     replacement: "x626C6F676765722E636F6D".to_string(),
                 }],
             },
+            auth_options: default_auth_options(),
+            access_options: default_access_options(),
+            upstream_options: default_upstream_options(),
+            shaping_options: default_shaping_options(),
+            strategy_profiles: default_strategy_profiles(),
+            geoip_options: default_geoip_options(),
             whitelist_sni: default_whitelist_sni(),
             whitelist_sni_list: whitelist_sni_list(),
             strategies: vec![Some(Strategy {
@@ -323,6 +632,10 @@ impl Default for AuxConfig {
     }
 }
 
+fn default_bind_mode() -> BindMode {
+    BindMode::Proxy
+}
+
 fn default_bind_host() -> String {
     "127.0.0.1".to_string()
 }
@@ -335,6 +648,10 @@ fn default_bind_iface() -> String {
     "default".to_string()
 }
 
+fn default_bind_tun_name() -> String {
+    "waterfall0".to_string()
+}
+
 fn default_bind_iface_mtu() -> u32 {
     1500
 }
@@ -347,6 +664,18 @@ fn default_bind_iface_ipv6() -> String {
     "::".to_string()
 }
 
+fn default_unix_permissions() -> String {
+    "600".to_string()
+}
+
+fn default_drain_timeout() -> u64 {
+    30000
+}
+
+fn default_listeners() -> Vec<ListenerOptions> {
+    vec![]
+}
+
 fn default_fake_packet_ttl() -> u8 {
     64
 }
@@ -399,6 +728,33 @@ fn default_l7_packet_jitter_max() -> u64 {
     0
 }
 
+fn default_connect_timeout() -> u64 {
+    10000
+}
+
+/* Handshake and idle timeouts are opt-in, long-lived quiet connections
+ * like SSH or push channels used to stay open forever
+ */
+fn default_handshake_timeout() -> u64 {
+    0
+}
+
+fn default_idle_timeout() -> u64 {
+    0
+}
+
+fn default_keepalive_interval() -> u64 {
+    15000
+}
+
+fn default_keepalive_count() -> u32 {
+    4
+}
+
+fn default_relay_mode() -> RelayMode {
+    RelayMode::Async
+}
+
 fn default_whitelist_sni() -> bool {
     true
 }
@@ -432,3 +788,72 @@ fn default_rules() -> Vec<RouterRule> {
 fn default_doh_servers() -> Vec<DohServer> {
     vec![]
 }
+
+fn default_auth_users() -> Vec<AuthUser> {
+    vec![]
+}
+
+fn default_auth_options() -> AuthOptions {
+    AuthOptions {
+        users: default_auth_users(),
+    }
+}
+
+fn default_access_action() -> AccessAction {
+    AccessAction::Allow
+}
+
+fn default_access_rules() -> Vec<AccessRule> {
+    vec![]
+}
+
+fn default_access_options() -> AccessOptions {
+    AccessOptions {
+        default_action: default_access_action(),
+        max_connections_per_client: 0,
+        rules: default_access_rules(),
+    }
+}
+
+fn default_pool_strategy() -> PoolStrategy {
+    PoolStrategy::RoundRobin
+}
+
+fn default_health_check_interval() -> u64 {
+    30000
+}
+
+fn default_upstream_pools() -> Vec<UpstreamPool> {
+    vec![]
+}
+
+fn default_upstream_options() -> UpstreamOptions {
+    UpstreamOptions {
+        health_check_interval: default_health_check_interval(),
+        pools: default_upstream_pools(),
+    }
+}
+
+fn default_strategy_profile_list() -> Vec<StrategyProfile> {
+    vec![]
+}
+
+fn default_strategy_profiles() -> StrategyProfiles {
+    StrategyProfiles {
+        profiles: default_strategy_profile_list(),
+    }
+}
+
+fn default_geoip_options() -> GeoIpOptions {
+    GeoIpOptions {
+        country_database: None,
+        asn_database: None,
+    }
+}
+
+fn default_shaping_options() -> ShapingOptions {
+    ShapingOptions {
+        global_rate: 0,
+        client_rate: 0,
+    }
+}
@@ -0,0 +1,451 @@
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+
+use log::{error, info};
+use regex::{Regex, RegexSet};
+
+use crate::aux_config::{AuxConfig, RouterRuleScope, WhiteListedSNIWrapper};
+use crate::strategy::FilterSniList;
+
+/* How an entry without a prefix is taken */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntryKind {
+    /* Only the domain itself */
+    Full,
+    /* The domain and everything below it */
+    Domain,
+    /* Any domain containing it */
+    Keyword,
+    Regex,
+}
+
+#[derive(Default)]
+struct Node {
+    children: HashMap<Box<str>, usize>,
+    exact: bool,
+    subdomains: bool,
+}
+
+/* Domains are kept in a trie of their labels, starting from the top level
+ * one, so a lookup costs as many steps as the domain has labels no matter
+ * how long the lists are
+ */
+pub struct DomainMatcher {
+    entries: usize,
+    nodes: Vec<Node>,
+    keywords: Vec<String>,
+    patterns: Vec<String>,
+    regexes: RegexSet,
+}
+
+impl Default for DomainMatcher {
+    fn default() -> Self {
+        DomainMatcher {
+            entries: 0,
+            nodes: vec![Node::default()],
+            keywords: vec![],
+            patterns: vec![],
+            regexes: RegexSet::empty(),
+        }
+    }
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim().trim_matches('.').to_ascii_lowercase()
+}
+
+fn is_glob(entry: &str) -> bool {
+    entry.contains(['*', '?', '['])
+}
+
+/* Globs that aren't a plain *.domain end up as regexes */
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '[' => {
+                regex.push('[');
+
+                if chars.next_if_eq(&'!').is_some() {
+                    regex.push('^');
+                }
+
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+
+                    if c == '\\' || c == '[' {
+                        regex.push('\\');
+                    }
+
+                    regex.push(c);
+                }
+
+                regex.push(']');
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    regex.push('$');
+
+    regex
+}
+
+impl DomainMatcher {
+    fn node(&mut self, domain: &str) -> &mut Node {
+        let mut index = 0;
+
+        for label in domain.rsplit('.').filter(|label| !label.is_empty()) {
+            index = match self.nodes[index].children.get(label) {
+                Some(child) => *child,
+                None => {
+                    self.nodes.push(Node::default());
+
+                    let child = self.nodes.len() - 1;
+
+                    self.nodes[index].children.insert(label.into(), child);
+
+                    child
+                }
+            };
+        }
+
+        &mut self.nodes[index]
+    }
+
+    /* Entries may say what they are with a full:, domain:, keyword: or regexp:
+     * prefix, or be globs. Regexes are only checked once everything is added
+     */
+    fn add(&mut self, entry: &str, plain: EntryKind) {
+        let entry = entry.trim();
+
+        let (kind, value) = if let Some(value) = entry.strip_prefix("full:") {
+            (EntryKind::Full, value)
+        } else if let Some(value) = entry.strip_prefix("domain:") {
+            (EntryKind::Domain, value)
+        } else if let Some(value) = entry.strip_prefix("keyword:") {
+            (EntryKind::Keyword, value)
+        } else if let Some(value) = entry.strip_prefix("regexp:") {
+            (EntryKind::Regex, value)
+        } else if let Some(domain) = entry.strip_prefix("*.").filter(|rest| !is_glob(rest)) {
+            self.node(&normalize(domain)).subdomains = true;
+
+            return;
+        } else if is_glob(entry) {
+            self.patterns
+                .push(glob_to_regex(&entry.to_ascii_lowercase()));
+
+            return;
+        } else {
+            (plain, entry)
+        };
+
+        match kind {
+            EntryKind::Regex => self.patterns.push(value.to_string()),
+            EntryKind::Keyword => self.keywords.push(value.trim().to_ascii_lowercase()),
+            EntryKind::Full => self.node(&normalize(value)).exact = true,
+            EntryKind::Domain => {
+                let domain = normalize(value);
+
+                /* Lists used to be matched by substring, so a bare word like
+                 * ytimg is still a keyword. A lone dot matches everything
+                 */
+                if !domain.is_empty() && !domain.contains('.') {
+                    self.keywords.push(domain);
+
+                    return;
+                }
+
+                let node = self.node(&domain);
+
+                node.exact = true;
+                node.subdomains = true;
+            }
+        }
+    }
+
+    pub fn build<'a>(entries: impl IntoIterator<Item = &'a str>, plain: EntryKind) -> Self {
+        let mut matcher = DomainMatcher::default();
+
+        for entry in entries {
+            matcher.add(entry, plain);
+
+            matcher.entries += 1;
+        }
+
+        matcher
+            .patterns
+            .retain(|pattern| match Regex::new(pattern) {
+                Ok(_) => true,
+                Err(e) => {
+                    error!("Skipping bad domain pattern {pattern}: {e}");
+
+                    false
+                }
+            });
+
+        matcher.regexes = match RegexSet::new(&matcher.patterns) {
+            Ok(regexes) => regexes,
+            Err(e) => {
+                error!("Failed to compile domain patterns: {e}");
+
+                RegexSet::empty()
+            }
+        };
+
+        matcher
+    }
+
+    pub fn matches(&self, domain: &str) -> bool {
+        let domain = normalize(domain);
+
+        if domain.is_empty() {
+            return false;
+        }
+
+        if self.nodes[0].subdomains {
+            return true;
+        }
+
+        let mut index = 0;
+        let mut labels = domain.rsplit('.').peekable();
+
+        while let Some(label) = labels.next() {
+            let Some(child) = self.nodes[index].children.get(label) else {
+                break;
+            };
+
+            index = *child;
+
+            let node = &self.nodes[index];
+
+            if labels.peek().is_none() {
+                if node.exact {
+                    return true;
+                }
+            } else if node.subdomains {
+                return true;
+            }
+        }
+
+        self.keywords
+            .iter()
+            .any(|keyword| domain.contains(keyword.as_str()))
+            || self.regexes.is_match(&domain)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/* Matchers are built along with the config and live until it or one of
+ * the lists gets reloaded
+ */
+static FILTERS: LazyLock<Mutex<HashMap<Vec<WhiteListedSNIWrapper>, Arc<DomainMatcher>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+static PATTERNS: LazyLock<Mutex<HashMap<String, Arc<DomainMatcher>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn filters(config: &AuxConfig) -> impl Iterator<Item = &FilterSniList> {
+    config
+        .strategies
+        .iter()
+        .flatten()
+        .chain(
+            config
+                .strategy_profiles
+                .profiles
+                .iter()
+                .flat_map(|profile| profile.strategies.iter().flatten()),
+        )
+        .map(|strategy| &strategy.filter_sni)
+}
+
+/* Router scopes whose match is an entry of a matcher, the others are
+ * ports, networks and codes
+ */
+fn patterns(config: &AuxConfig) -> impl Iterator<Item = &str> {
+    config
+        .router_options
+        .rules
+        .iter()
+        .filter(|rule| {
+            matches!(
+                rule.scope,
+                RouterRuleScope::DnsQuery
+                    | RouterRuleScope::SNI
+                    | RouterRuleScope::User
+                    | RouterRuleScope::Listener
+            )
+        })
+        .map(|rule| rule.rule_match.as_str())
+}
+
+/* Large lists take a while to compile, so the locks are only taken to swap
+ * the new matchers in
+ */
+pub fn rebuild(config: &AuxConfig) {
+    let mut matchers = HashMap::new();
+
+    for filter in filters(config) {
+        if !matchers.contains_key(filter.items.as_slice()) {
+            matchers.insert(filter.items.clone(), Arc::new(filter.build()));
+        }
+    }
+
+    let mut rule_matchers = HashMap::new();
+
+    for rule_match in patterns(config) {
+        if !rule_matchers.contains_key(rule_match) {
+            rule_matchers.insert(rule_match.to_string(), Arc::new(build_pattern(rule_match)));
+        }
+    }
+
+    *FILTERS.lock().unwrap() = matchers;
+    *PATTERNS.lock().unwrap() = rule_matchers;
+}
+
+impl FilterSniList {
+    fn build(&self) -> DomainMatcher {
+        let lists: Vec<_> = self.items.iter().map(|item| item.domains()).collect();
+
+        let matcher = DomainMatcher::build(
+            lists
+                .iter()
+                .flat_map(|list| list.iter().map(String::as_str)),
+            EntryKind::Domain,
+        );
+
+        if matcher.len() > 1000 {
+            info!("Compiled a domain filter of {} entries", matcher.len());
+        }
+
+        matcher
+    }
+
+    /* Filters of the loaded config are all built already, anything else is
+     * built here without holding the lock
+     */
+    pub fn matcher(&self) -> Arc<DomainMatcher> {
+        if let Some(matcher) = FILTERS.lock().unwrap().get(self.items.as_slice()) {
+            return matcher.clone();
+        }
+
+        let matcher = Arc::new(self.build());
+
+        FILTERS
+            .lock()
+            .unwrap()
+            .insert(self.items.clone(), matcher.clone());
+
+        matcher
+    }
+}
+
+/* A router rule match is a single entry, where a plain domain only matches itself */
+fn build_pattern(rule_match: &str) -> DomainMatcher {
+    DomainMatcher::build([rule_match], EntryKind::Full)
+}
+
+/* Rules of the loaded config are all built already, like filters */
+pub fn pattern(rule_match: &str) -> Arc<DomainMatcher> {
+    if let Some(matcher) = PATTERNS.lock().unwrap().get(rule_match) {
+        return matcher.clone();
+    }
+
+    let matcher = Arc::new(build_pattern(rule_match));
+
+    PATTERNS
+        .lock()
+        .unwrap()
+        .insert(rule_match.to_string(), matcher.clone());
+
+    matcher
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(entries: &[&str]) -> DomainMatcher {
+        DomainMatcher::build(entries.iter().copied(), EntryKind::Domain)
+    }
+
+    #[test]
+    fn trie_matches_by_labels() {
+        let matcher = matcher(&["example.com", "full:exact.org", "*.sub.net"]);
+
+        assert!(matcher.matches("example.com"));
+        assert!(matcher.matches("www.Example.com."));
+        assert!(!matcher.matches("notexample.com"));
+        assert!(!matcher.matches("com"));
+
+        assert!(matcher.matches("exact.org"));
+        assert!(!matcher.matches("www.exact.org"));
+
+        assert!(matcher.matches("a.sub.net"));
+        assert!(!matcher.matches("sub.net"));
+
+        assert_eq!(matcher.len(), 3);
+    }
+
+    #[test]
+    fn plain_entries_follow_the_kind() {
+        let matcher = DomainMatcher::build(["example.com"], EntryKind::Full);
+
+        assert!(matcher.matches("example.com"));
+        assert!(!matcher.matches("www.example.com"));
+    }
+
+    #[test]
+    fn bare_words_and_keywords_match_substrings() {
+        let matcher = matcher(&["ytimg", "keyword:Video"]);
+
+        assert!(matcher.matches("i.ytimg.com"));
+        assert!(matcher.matches("rr1.googlevideo.com"));
+        assert!(!matcher.matches("youtube.com"));
+    }
+
+    #[test]
+    fn lone_dot_matches_everything() {
+        let matcher = matcher(&["."]);
+
+        assert!(matcher.matches("example.com"));
+        assert!(!matcher.matches(""));
+    }
+
+    #[test]
+    fn globs_and_regexes_match_whole_domains() {
+        let matcher = matcher(&[
+            "cdn?.example.*",
+            "regexp:^rr[0-9]+\\.googlevideo\\.com$",
+            "regexp:(",
+        ]);
+
+        assert!(matcher.matches("cdn1.example.org"));
+        assert!(!matcher.matches("cdn12.example.org"));
+        assert!(matcher.matches("rr5.googlevideo.com"));
+        assert!(!matcher.matches("rr.googlevideo.com"));
+    }
+
+    #[test]
+    fn glob_to_regex_escapes_and_translates() {
+        assert_eq!(glob_to_regex("*.example.com"), "^.*\\.example\\.com$");
+        assert_eq!(glob_to_regex("cdn?.net"), "^cdn.\\.net$");
+        assert_eq!(glob_to_regex("[a-c]x.org"), "^[a-c]x\\.org$");
+        assert_eq!(glob_to_regex("[!0-9]x.org"), "^[^0-9]x\\.org$");
+        assert_eq!(glob_to_regex("a+b.com"), "^a\\+b\\.com$");
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+
+use ipnetwork::IpNetwork;
+use log::{error, info};
+use maxminddb::{geoip2, Reader};
+
+use crate::aux_config::{AuxConfig, GeoIpOptions};
+use crate::sni_list::{is_in_dir, parent_dir, resolve_path};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GeoField {
+    /* ISO code of the country, like RU */
+    Country,
+    /* Number of the autonomous system, like 13335 */
+    Asn,
+}
+
+/* IPv4 is kept as mapped IPv6, so both fit one sorted list of ranges.
+ * Ranges of a CSV database shouldn't overlap, like in the GeoLite2 ones
+ */
+struct Ranges(Vec<(u128, u128, String)>);
+
+enum Database {
+    MaxMind(Reader<Vec<u8>>),
+    Csv(Ranges),
+}
+
+fn to_u128(ip: IpAddr) -> u128 {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().into(),
+        IpAddr::V6(ip) => ip.into(),
+    }
+}
+
+impl Ranges {
+    /* The network comes first and the value second, other columns and
+     * lines that aren't a network, like headers, are skipped
+     */
+    fn parse(text: &str) -> Ranges {
+        let mut ranges: Vec<(u128, u128, String)> = text
+            .lines()
+            .filter_map(|line| {
+                let mut columns = line
+                    .split('#')
+                    .next()
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|column| column.trim().trim_matches('"'));
+
+                let network = columns.next()?.parse::<IpNetwork>().ok()?;
+                let value = columns.next().filter(|value| !value.is_empty())?;
+
+                Some((
+                    to_u128(network.network()),
+                    to_u128(network.broadcast()),
+                    value.to_string(),
+                ))
+            })
+            .collect();
+
+        ranges.sort_by_key(|(start, _, _)| *start);
+
+        Ranges(ranges)
+    }
+
+    fn lookup(&self, ip: IpAddr) -> Option<&str> {
+        let ip = to_u128(ip);
+
+        let index = self.0.partition_point(|(start, _, _)| *start <= ip);
+
+        let (_, end, value) = self.0.get(index.checked_sub(1)?)?;
+
+        (ip <= *end).then_some(value.as_str())
+    }
+}
+
+impl Database {
+    fn open(path: &Path) -> Option<Database> {
+        let is_csv = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+
+        let database = if is_csv {
+            fs::read_to_string(path)
+                .map(|text| Database::Csv(Ranges::parse(&text)))
+                .map_err(|e| e.to_string())
+        } else {
+            Reader::open_readfile(path)
+                .map(Database::MaxMind)
+                .map_err(|e| e.to_string())
+        };
+
+        match database {
+            Ok(database) => {
+                match &database {
+                    Database::Csv(ranges) => {
+                        info!("Loaded {} networks from {path:?}", ranges.0.len())
+                    }
+                    Database::MaxMind(reader) => info!(
+                        "Loaded {} database from {path:?}",
+                        reader.metadata.database_type
+                    ),
+                }
+
+                Some(database)
+            }
+            Err(e) => {
+                error!("Failed to load GeoIP database {path:?}: {e}");
+
+                None
+            }
+        }
+    }
+
+    fn lookup(&self, field: GeoField, ip: IpAddr) -> Option<String> {
+        let ip = ip.to_canonical();
+
+        match self {
+            Database::Csv(ranges) => ranges.lookup(ip).map(str::to_string),
+            Database::MaxMind(reader) => match field {
+                GeoField::Country => {
+                    let country = reader.lookup::<geoip2::Country>(ip).ok()?;
+
+                    country
+                        .country
+                        .or(country.registered_country)?
+                        .iso_code
+                        .map(str::to_string)
+                }
+                GeoField::Asn => reader
+                    .lookup::<geoip2::Asn>(ip)
+                    .ok()?
+                    .autonomous_system_number
+                    .map(|number| number.to_string()),
+            },
+        }
+    }
+}
+
+/* Databases are opened along with the config and whenever they change on
+ * disk, keyed by what they answer so a lookup only takes the lock. One that
+ * failed to load stays missing until then, so the error isn't logged on
+ * every connection
+ */
+static DATABASES: LazyLock<Mutex<HashMap<GeoField, Arc<Database>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn database_path(options: &GeoIpOptions, field: GeoField) -> Option<&String> {
+    match field {
+        GeoField::Country => options.country_database.as_ref(),
+        GeoField::Asn => options.asn_database.as_ref(),
+    }
+}
+
+pub fn lookup(field: GeoField, ip: IpAddr) -> Option<String> {
+    let database = DATABASES.lock().unwrap().get(&field).cloned()?;
+
+    database.lookup(field, ip)
+}
+
+/* Opens every database of the config and swaps them in at once, a file
+ * answering both fields is opened once
+ */
+pub fn reload(config: &AuxConfig) {
+    let mut opened: HashMap<PathBuf, Option<Arc<Database>>> = HashMap::new();
+    let mut loaded = HashMap::new();
+
+    for field in [GeoField::Country, GeoField::Asn] {
+        let Some(path) = database_path(&config.geoip_options, field) else {
+            continue;
+        };
+
+        let database = opened
+            .entry(resolve_path(path))
+            .or_insert_with_key(|path| Database::open(path).map(Arc::new));
+
+        if let Some(database) = database {
+            loaded.insert(field, database.clone());
+        }
+    }
+
+    *DATABASES.lock().unwrap() = loaded;
+}
+
+fn databases(config: &AuxConfig) -> impl Iterator<Item = PathBuf> + '_ {
+    [GeoField::Country, GeoField::Asn]
+        .into_iter()
+        .filter_map(|field| database_path(&config.geoip_options, field))
+        .map(|path| resolve_path(path))
+}
+
+pub fn watched_paths(config: &AuxConfig) -> Vec<PathBuf> {
+    databases(config)
+        .filter_map(|path| parent_dir(&path))
+        .collect()
+}
+
+pub fn is_database_path(config: &AuxConfig, path: &Path) -> bool {
+    databases(config).any(|database| {
+        database.file_name() == path.file_name()
+            && parent_dir(&database).is_some_and(|dir| is_in_dir(&dir, path))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CSV: &str = "network,country_iso_code\n\
+        \"1.0.0.0/24\",AU\n\
+        5.8.0.0/19,RU # comment\n\
+        2001:db8::/32,ZZ\n\
+        10.0.0.0/8,\n\
+        garbage,XX\n";
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_skips_headers_and_empty_values() {
+        let ranges = Ranges::parse(CSV);
+
+        assert_eq!(ranges.0.len(), 3);
+        assert!(ranges.0.is_sorted_by_key(|(start, _, _)| *start));
+    }
+
+    #[test]
+    fn lookup_finds_the_enclosing_range() {
+        let ranges = Ranges::parse(CSV);
+
+        assert_eq!(ranges.lookup(ip("1.0.0.0")), Some("AU"));
+        assert_eq!(ranges.lookup(ip("1.0.0.255")), Some("AU"));
+        assert_eq!(ranges.lookup(ip("5.8.31.1")), Some("RU"));
+        assert_eq!(ranges.lookup(ip("2001:db8::1")), Some("ZZ"));
+
+        assert_eq!(ranges.lookup(ip("1.0.1.0")), None);
+        assert_eq!(ranges.lookup(ip("0.255.255.255")), None);
+        assert_eq!(ranges.lookup(ip("10.1.2.3")), None);
+        assert_eq!(ranges.lookup(ip("2001:db9::1")), None);
+    }
+
+    #[test]
+    fn mapped_ipv6_matches_ipv4_ranges() {
+        let database = Database::Csv(Ranges::parse(CSV));
+
+        assert_eq!(
+            database.lookup(GeoField::Country, ip("::ffff:5.8.1.1")),
+            Some("RU".to_string())
+        );
+        assert_eq!(
+            database.lookup(GeoField::Country, ip("::ffff:1.0.1.0")),
+            None
+        );
+    }
+}
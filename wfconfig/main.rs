@@ -2,12 +2,15 @@ use serde::{Deserialize, Serialize};
 
 pub mod arg_config;
 pub mod aux_config;
+pub mod domain_matcher;
+pub mod geoip;
+pub mod sni_list;
 pub mod strategy;
 pub mod weak_range;
 
 use std::fs::{self, File};
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{LazyLock, Mutex};
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
@@ -24,6 +27,28 @@ use crate::arg_config::Args;
 
 static CONFIG: LazyLock<Mutex<Option<AuxConfig>>> = LazyLock::new(|| Mutex::new(None));
 
+/* Reloads asked for by signals, served by the watcher task so the data
+ * files of the new config get watched too
+ */
+#[cfg(not(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "riscv64",
+    target_arch = "s390x",
+    target_arch = "sparc64",
+    target_arch = "loongarch64",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly",
+    target_env = "musl",
+)))]
+static RELOAD_REQUESTS: tokio::sync::Notify = tokio::sync::Notify::const_new();
+
 use crate::aux_config::AuxConfig;
 
 use log::{debug, error, info};
@@ -47,7 +72,7 @@ use log::{debug, error, info};
 )))]
 use notify::{RecommendedWatcher, RecursiveMode, Result, Watcher};
 
-use futures::{SinkExt, StreamExt};
+use futures::StreamExt;
 
 #[cfg(not(any(
     target_arch = "mips",
@@ -67,33 +92,69 @@ use futures::{SinkExt, StreamExt};
     target_env = "musl",
 )))]
 pub async fn core_launch_task() -> Result<()> {
-    let (mut tx, mut rx) = mpsc::channel(1);
+    /* Unbounded, since watch() waits for the notify thread, which must never
+     * wait for this task in turn
+     */
+    let (tx, mut rx) = mpsc::unbounded();
 
     let mut watcher = RecommendedWatcher::new(
         move |res| {
-            futures::executor::block_on(async {
-                tx.send(res).await.unwrap();
-            })
+            let _ = tx.unbounded_send(res);
         },
         notify::Config::default(),
     )?;
 
     let path = Args::parse().config;
 
-    watcher.watch(Path::new(&path), RecursiveMode::NonRecursive)?;
+    /* Events carry the path as it was watched, so it has to be comparable */
+    let config_path = fs::canonicalize(&path).unwrap_or(path);
 
-    while let Some(res) = rx.next().await {
-        match res {
-            Ok(_) => {
-                let mut lock = match CONFIG.lock() {
-                    Err(e) => e.into_inner(),
-                    Ok(guard) => guard,
-                };
+    watcher.watch(Path::new(&config_path), RecursiveMode::NonRecursive)?;
+
+    let mut data_paths = vec![];
 
-                *lock = Some(load_config());
+    watch_data_files(&mut watcher, &mut data_paths);
 
-                info!("Config hot-reloaded!");
+    loop {
+        let res = tokio::select! {
+            res = rx.next() => match res {
+                Some(res) => res,
+                None => break,
+            },
+            _ = RELOAD_REQUESTS.notified() => {
+                reload_and_watch(&mut watcher, &mut data_paths);
+
+                continue;
+            }
+        };
+
+        match res {
+            /* Reloading reads the file, which must not trigger yet another reload */
+            Ok(event) if event.kind.is_access() => {}
+            Ok(event) if event.paths.iter().any(|path| path == &config_path) => {
+                reload_and_watch(&mut watcher, &mut data_paths);
             }
+            Ok(event)
+                if event
+                    .paths
+                    .iter()
+                    .any(|path| sni_list::is_list_path(&parse_args(), path)) =>
+            {
+                debug!("SNI lists have changed on disk");
+
+                sni_list::reload(&parse_args());
+            }
+            Ok(event)
+                if event
+                    .paths
+                    .iter()
+                    .any(|path| geoip::is_database_path(&parse_args(), path)) =>
+            {
+                debug!("GeoIP databases have changed on disk");
+
+                geoip::reload(&parse_args());
+            }
+            Ok(_) => {}
 
             Err(e) => error!("Error while watching the config file: {e:?}"),
         }
@@ -102,6 +163,89 @@ pub async fn core_launch_task() -> Result<()> {
     Ok(())
 }
 
+#[cfg(not(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "riscv64",
+    target_arch = "s390x",
+    target_arch = "sparc64",
+    target_arch = "loongarch64",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly",
+    target_env = "musl",
+)))]
+fn reload_and_watch(watcher: &mut RecommendedWatcher, watched: &mut Vec<PathBuf>) {
+    reload_config();
+
+    watch_data_files(watcher, watched);
+}
+
+#[cfg(not(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "riscv64",
+    target_arch = "s390x",
+    target_arch = "sparc64",
+    target_arch = "loongarch64",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly",
+    target_env = "musl",
+)))]
+pub fn request_reload() {
+    RELOAD_REQUESTS.notify_one();
+}
+
+#[cfg(not(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "riscv64",
+    target_arch = "s390x",
+    target_arch = "sparc64",
+    target_arch = "loongarch64",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly",
+    target_env = "musl",
+)))]
+fn watch_data_files(watcher: &mut RecommendedWatcher, watched: &mut Vec<PathBuf>) {
+    let config = parse_args();
+
+    let mut paths = sni_list::watched_paths(&config);
+
+    paths.extend(geoip::watched_paths(&config));
+    paths.sort();
+    paths.dedup();
+
+    for path in watched.iter().filter(|path| !paths.contains(path)) {
+        let _ = watcher.unwatch(path);
+    }
+
+    for path in paths.iter().filter(|path| !watched.contains(path)) {
+        if let Err(e) = watcher.watch(path, RecursiveMode::NonRecursive) {
+            error!("Failed to watch {path:?}: {e:?}");
+        }
+    }
+
+    *watched = paths;
+}
+
 #[cfg(any(
     target_arch = "mips",
     target_arch = "mips64",
@@ -123,6 +267,27 @@ pub async fn core_launch_task() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[cfg(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "riscv64",
+    target_arch = "s390x",
+    target_arch = "sparc64",
+    target_arch = "loongarch64",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly",
+    target_env = "musl",
+))]
+pub fn request_reload() {
+    reload_config();
+}
+
 fn create_config() {
     let path = Args::parse().config;
 
@@ -172,10 +337,41 @@ fn load_config() -> AuxConfig {
         AuxConfig::default()
     });
 
+    /* The data files go along with the config, before anything can use it */
+    sni_list::reload(&config);
+    geoip::reload(&config);
+
     config
 }
 
+pub fn reload_config() {
+    let config = load_config();
+
+    let mut lock = match CONFIG.lock() {
+        Err(e) => e.into_inner(),
+        Ok(guard) => guard,
+    };
+
+    *lock = Some(config);
+
+    info!("Config hot-reloaded!");
+}
+
+tokio::task_local! {
+    /* Set while a desync hook runs with a strategy profile */
+    static SCOPED_CONFIG: AuxConfig;
+}
+
+/* Everything calling parse_args() within the future sees this config instead */
+pub async fn with_config<F: std::future::Future>(config: AuxConfig, future: F) -> F::Output {
+    SCOPED_CONFIG.scope(config, future).await
+}
+
 pub fn parse_args() -> AuxConfig {
+    if let Ok(config) = SCOPED_CONFIG.try_with(|config| config.clone()) {
+        return config;
+    }
+
     let mut lock = match CONFIG.lock() {
         Err(e) => e.into_inner(),
         Ok(guard) => guard,
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+
+use log::{error, info};
+
+use crate::arg_config::Args;
+use crate::aux_config::{AuxConfig, WhiteListedSNI, WhiteListedSNIWrapper};
+use crate::domain_matcher;
+
+/* Lists are read along with the config and whenever they change on disk,
+ * so connections never wait for the disk
+ */
+static LISTS: LazyLock<Mutex<HashMap<PathBuf, Arc<Vec<String>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/* Relative list paths start from the directory of the config */
+pub fn resolve_path(value: &str) -> PathBuf {
+    let path = Path::new(value);
+
+    if path.is_absolute() {
+        return path.to_path_buf();
+    }
+
+    match Args::parse().config.parent() {
+        Some(parent) => parent.join(path),
+        None => path.to_path_buf(),
+    }
+}
+
+/* One domain per line, everything after a '#' is a comment */
+fn parse_list(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn read_list(path: &Path) -> Vec<String> {
+    match fs::read_to_string(path) {
+        Ok(text) => parse_list(&text),
+        Err(e) => {
+            error!("Failed to read SNI list {path:?}: {e}");
+
+            vec![]
+        }
+    }
+}
+
+/* Every file of the directory is a list, except hidden ones like editor swap files */
+fn read_list_dir(path: &Path) -> Vec<String> {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Failed to read SNI list directory {path:?}: {e}");
+
+            return vec![];
+        }
+    };
+
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect();
+
+    files.sort();
+
+    files.iter().flat_map(|file| read_list(file)).collect()
+}
+
+impl WhiteListedSNIWrapper {
+    pub fn domains(&self) -> Arc<Vec<String>> {
+        match self.list {
+            WhiteListedSNI::Domain => Arc::new(vec![self.value.clone()]),
+            WhiteListedSNI::File | WhiteListedSNI::Path => LISTS
+                .lock()
+                .unwrap()
+                .get(&resolve_path(&self.value))
+                .cloned()
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/* Reads every list of the config and swaps them in at once. Matchers hold
+ * the domains of the lists, so they go too
+ */
+pub fn reload(config: &AuxConfig) {
+    let mut lists = HashMap::new();
+
+    for source in sources(config) {
+        if source.list == WhiteListedSNI::Domain {
+            continue;
+        }
+
+        let path = resolve_path(&source.value);
+
+        if lists.contains_key(&path) {
+            continue;
+        }
+
+        let domains = match source.list {
+            WhiteListedSNI::Path => read_list_dir(&path),
+            _ => read_list(&path),
+        };
+
+        info!("Loaded {} domains from {path:?}", domains.len());
+
+        lists.insert(path, Arc::new(domains));
+    }
+
+    *LISTS.lock().unwrap() = lists;
+
+    domain_matcher::rebuild(config);
+}
+
+/* Only strategy filters read lists, the global whitelist isn't applied anywhere */
+fn sources(config: &AuxConfig) -> impl Iterator<Item = &WhiteListedSNIWrapper> {
+    config
+        .strategies
+        .iter()
+        .flatten()
+        .flat_map(|strategy| strategy.filter_sni.items.iter())
+        .chain(
+            config
+                .strategy_profiles
+                .profiles
+                .iter()
+                .flat_map(|profile| profile.strategies.iter().flatten())
+                .flat_map(|strategy| strategy.filter_sni.items.iter()),
+        )
+}
+
+/* A file is watched through its directory, since editors tend to replace
+ * files instead of writing them
+ */
+pub(crate) fn parent_dir(path: &Path) -> Option<PathBuf> {
+    path.parent().map(|parent| {
+        if parent.as_os_str().is_empty() {
+            PathBuf::from(".")
+        } else {
+            parent.to_path_buf()
+        }
+    })
+}
+
+/* Event paths and configured ones are spelled differently, so both sides are canonicalized */
+pub(crate) fn is_in_dir(dir: &Path, path: &Path) -> bool {
+    let Some(parent) = path
+        .parent()
+        .and_then(|parent| fs::canonicalize(parent).ok())
+    else {
+        return false;
+    };
+
+    fs::canonicalize(dir).is_ok_and(|dir| dir == parent)
+}
+
+fn source_dir(source: &WhiteListedSNIWrapper) -> Option<PathBuf> {
+    match source.list {
+        WhiteListedSNI::Domain => None,
+        WhiteListedSNI::File => parent_dir(&resolve_path(&source.value)),
+        WhiteListedSNI::Path => Some(resolve_path(&source.value)),
+    }
+}
+
+pub fn watched_paths(config: &AuxConfig) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = sources(config).filter_map(source_dir).collect();
+
+    paths.sort();
+    paths.dedup();
+
+    paths
+}
+
+/* Watched directories may hold anything else too, like the log of the
+ * proxy itself, so only events on the lists count
+ */
+pub fn is_list_path(config: &AuxConfig, path: &Path) -> bool {
+    let Some(name) = path.file_name() else {
+        return false;
+    };
+
+    sources(config).any(|source| {
+        let in_source_dir = source_dir(source).is_some_and(|dir| is_in_dir(&dir, path));
+
+        match source.list {
+            WhiteListedSNI::Domain => false,
+            WhiteListedSNI::File => {
+                in_source_dir && resolve_path(&source.value).file_name() == Some(name)
+            }
+            WhiteListedSNI::Path => in_source_dir && !name.to_string_lossy().starts_with('.'),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_list_skips_comments_and_blank_lines() {
+        let text = "# discord\ndiscord.com\n\n  discord.gg  # invites\n   \n#cdn.discordapp.com\nfull:discord.media\n";
+
+        assert_eq!(
+            parse_list(text),
+            vec!["discord.com", "discord.gg", "full:discord.media"]
+        );
+    }
+
+    #[test]
+    fn list_dir_skips_hidden_files_and_directories() {
+        let dir = std::env::temp_dir().join(format!("wf-sni-list-{}", std::process::id()));
+
+        fs::create_dir_all(dir.join("nested")).unwrap();
+
+        fs::write(dir.join("b.txt"), "b.com\n").unwrap();
+        fs::write(dir.join("a.txt"), "a.com # first\n").unwrap();
+        fs::write(dir.join(".a.txt.swp"), "swap.com\n").unwrap();
+        fs::write(dir.join("nested").join("c.txt"), "c.com\n").unwrap();
+
+        let domains = read_list_dir(&dir);
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(domains, vec!["a.com", "b.com"]);
+    }
+}
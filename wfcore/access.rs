@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{LazyLock, Mutex};
+
+use wfconfig::aux_config::{AccessAction, AuxConfig};
+
+static CLIENT_CONNECTIONS: LazyLock<Mutex<HashMap<IpAddr, usize>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/* Holds one of the connections a client is allowed to have open,
+ * the slot is given back once the whole session is dropped
+ */
+pub struct ClientSlot {
+    ip: IpAddr,
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        let mut connections = CLIENT_CONNECTIONS.lock().unwrap();
+
+        if let Some(count) = connections.get_mut(&self.ip) {
+            *count -= 1;
+
+            if *count == 0 {
+                connections.remove(&self.ip);
+            }
+        }
+    }
+}
+
+pub struct AccessControl();
+
+impl AccessControl {
+    /* Rules are evaluated in order and the first matching CIDR wins */
+    pub fn is_allowed(config: &AuxConfig, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+
+        let options = &config.access_options;
+
+        for rule in &options.rules {
+            if rule.cidr.contains(ip) {
+                return rule.action == AccessAction::Allow;
+            }
+        }
+
+        options.default_action == AccessAction::Allow
+    }
+
+    pub fn acquire_slot(config: &AuxConfig, ip: IpAddr) -> Option<ClientSlot> {
+        let ip = ip.to_canonical();
+
+        let limit = config.access_options.max_connections_per_client;
+
+        let mut connections = CLIENT_CONNECTIONS.lock().unwrap();
+
+        let count = connections.entry(ip).or_insert(0);
+
+        if limit != 0 && *count >= limit {
+            return None;
+        }
+
+        *count += 1;
+
+        Some(ClientSlot { ip })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wfconfig::aux_config::AccessRule;
+
+    fn rule(action: AccessAction, cidr: &str) -> AccessRule {
+        AccessRule {
+            action,
+            cidr: cidr.parse().unwrap(),
+        }
+    }
+
+    fn config(default_action: AccessAction, rules: Vec<AccessRule>, limit: usize) -> AuxConfig {
+        let mut config = AuxConfig::default();
+
+        config.access_options.default_action = default_action;
+        config.access_options.rules = rules;
+        config.access_options.max_connections_per_client = limit;
+
+        config
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let config = config(
+            AccessAction::Deny,
+            vec![
+                rule(AccessAction::Deny, "192.168.1.13/32"),
+                rule(AccessAction::Allow, "192.168.1.0/24"),
+                rule(AccessAction::Allow, "fd00::/8"),
+            ],
+            0,
+        );
+
+        assert!(!AccessControl::is_allowed(&config, ip("192.168.1.13")));
+        assert!(AccessControl::is_allowed(&config, ip("192.168.1.14")));
+        assert!(AccessControl::is_allowed(&config, ip("fd00::1")));
+
+        assert!(!AccessControl::is_allowed(&config, ip("10.0.0.1")));
+        assert!(!AccessControl::is_allowed(&config, ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn default_action_applies_without_a_match() {
+        let config = config(
+            AccessAction::Allow,
+            vec![rule(AccessAction::Deny, "10.0.0.0/8")],
+            0,
+        );
+
+        assert!(!AccessControl::is_allowed(&config, ip("10.1.2.3")));
+        assert!(AccessControl::is_allowed(&config, ip("172.16.0.1")));
+    }
+
+    #[test]
+    fn mapped_ipv6_clients_match_ipv4_rules() {
+        let config = config(
+            AccessAction::Deny,
+            vec![rule(AccessAction::Allow, "127.0.0.0/8")],
+            0,
+        );
+
+        assert!(AccessControl::is_allowed(&config, ip("::ffff:127.0.0.1")));
+    }
+
+    #[test]
+    fn slots_are_limited_and_given_back() {
+        let config = config(AccessAction::Allow, vec![], 2);
+
+        let client = ip("198.51.100.1");
+
+        let first = AccessControl::acquire_slot(&config, client).unwrap();
+        let second = AccessControl::acquire_slot(&config, client).unwrap();
+
+        assert!(AccessControl::acquire_slot(&config, client).is_none());
+
+        /* Other clients have their own count, a mapped address counts as IPv4 */
+        let other = AccessControl::acquire_slot(&config, ip("198.51.100.2")).unwrap();
+
+        assert!(AccessControl::acquire_slot(&config, ip("::ffff:198.51.100.1")).is_none());
+
+        drop(first);
+
+        let third = AccessControl::acquire_slot(&config, client).unwrap();
+
+        drop((second, third, other));
+
+        assert!(!CLIENT_CONNECTIONS.lock().unwrap().contains_key(&client));
+    }
+
+    #[test]
+    fn zero_means_no_limit() {
+        let config = config(AccessAction::Allow, vec![], 0);
+
+        let slots: Vec<ClientSlot> = (0..64)
+            .map(|_| AccessControl::acquire_slot(&config, ip("198.51.100.3")).unwrap())
+            .collect();
+
+        assert_eq!(slots.len(), 64);
+    }
+}
@@ -1,2 +1,6 @@
+pub mod access;
+pub mod relay;
 pub mod router;
+pub mod shaping;
 pub mod socket;
+pub mod upstream;
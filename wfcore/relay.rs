@@ -0,0 +1,18 @@
+use wfconfig::aux_config::RelayMode;
+
+use crate::shaping::Shaper;
+use crate::upstream::pool::Lease;
+
+/* What the router decided about the relay of a connection, on top of where it goes.
+ * It travels along with the stream, so whatever it holds lasts as long as the relay
+ */
+#[derive(Default)]
+pub struct RelayPolicy {
+    pub shaper: Shaper,
+    /* None leaves it to the global relay mode */
+    pub mode: Option<RelayMode>,
+    /* Strategy profile the desync hooks run with */
+    pub profile: Option<String>,
+    /* Counts the connection against its pool member */
+    pub lease: Option<Lease>,
+}
@@ -1,101 +1,617 @@
-use glob::Pattern;
+use std::net::{IpAddr, SocketAddr};
+
+use ipnetwork::IpNetwork;
 use wfcipu::parsers::ip::IpParser;
 
-use wfconfig::aux_config::{AuxConfig, RouterRule, RouterRuleScope, RouterRuleType};
+use wfconfig::aux_config::{AuxConfig, RelayMode, RouterRule, RouterRuleScope, RouterRuleType};
+use wfconfig::geoip::{self, GeoField};
+use wfconfig::{domain_matcher, NetworkProtocol};
 
 use anyhow::Result;
 
-use log::error;
+use log::{info, warn};
+use wfblmark::is_16kb_blocked;
+
+use crate::upstream::UpstreamProtocol;
 
 pub struct Router();
 
+#[derive(Debug, Clone, Default)]
+pub struct ClientIdentity {
+    pub user: Option<String>,
+    pub listener: Option<String>,
+    pub client_ip: Option<IpAddr>,
+}
+
+impl ClientIdentity {
+    pub fn matches_user(&self, rule_match: &str) -> bool {
+        let Some(ref user) = self.user else {
+            return false;
+        };
+
+        domain_matcher::pattern(rule_match).matches(user)
+    }
+
+    pub fn matches_listener(&self, rule_match: &str) -> bool {
+        self.listener
+            .as_deref()
+            .is_some_and(|listener| domain_matcher::pattern(rule_match).matches(listener))
+    }
+}
+
+/* Everything the router knows about a connection so far. A rule asking
+ * for something that isn't known yet doesn't match
+ */
+#[derive(Debug, Clone, Default)]
+pub struct RouteContext {
+    pub domain: Option<String>,
+    pub sni: Option<String>,
+    pub ip: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub protocol: Option<NetworkProtocol>,
+    pub identity: ClientIdentity,
+}
+
+impl RouteContext {
+    /* The name upstreams and logs get for the target */
+    pub fn host(&self) -> Option<&str> {
+        self.domain.as_deref().or(self.sni.as_deref())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum RouteAction {
+    #[default]
+    Direct,
+    Block,
+    Proxy {
+        protocol: UpstreamProtocol,
+        remote_dns: bool,
+        proxy: String,
+    },
+    Pool(String),
+}
+
+/* Where a connection goes, decided by the first routing rule, and how,
+ * collected from the ratelimit and relay rules matched on the way there
+ */
+#[derive(Debug, Default, PartialEq)]
+pub struct RouteDecision {
+    pub action: RouteAction,
+    /* Answer of a FakeDNS rule */
+    pub address: Option<IpAddr>,
+    /* Every matching ratelimit rule adds its own limit */
+    pub rate_limits: Vec<(String, u64)>,
+    /* The first matching relay rule wins */
+    pub relay_mode: Option<RelayMode>,
+    /* Strategy profile of a direct connection, None keeps the global strategies */
+    pub profile: Option<String>,
+}
+
+/* What applying a matched rule did to the decision */
+#[derive(Debug, PartialEq)]
+enum RuleOutcome {
+    /* The rule can't be applied and is skipped */
+    Skipped,
+    /* A ratelimit or relay rule, the search goes on */
+    Modified,
+    /* The rule picked the route and ends the search */
+    Routed,
+}
+
 pub enum RouterInterjectionStatus {
     Allow,
     AutoResolved(IpParser),
 }
 
+/* Ports are written as a comma separated list of ports and ranges, like 80,443,8000-8080 */
+fn matches_port(rule_match: &str, port: u16) -> bool {
+    rule_match
+        .split(',')
+        .any(|item| match item.trim().split_once('-') {
+            Some((start, end)) => match (start.trim().parse::<u16>(), end.trim().parse::<u16>()) {
+                (Ok(start), Ok(end)) => (start..=end).contains(&port),
+                _ => false,
+            },
+            None => item.trim().parse::<u16>() == Ok(port),
+        })
+}
+
+/* Systems are written with or without the AS prefix, like AS13335 or 13335 */
+fn matches_asn(item: &str, asn: &str) -> bool {
+    let item = item.trim();
+
+    let number = match item.get(..2) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("as") => &item[2..],
+        _ => item,
+    };
+
+    number == asn
+}
+
 impl Router {
-    pub fn query_router_rules<'a>(
-        config: &'a AuxConfig,
-        rule_type: &RouterRuleType,
-    ) -> Vec<&'a RouterRule> {
-        config
-            .router_options
-            .rules
-            .iter()
-            .filter(|n| &n.rule_type == rule_type)
-            .collect()
-    }
-
-    pub fn router_process<'a>(
-        ref rule_type: RouterRuleType,
-        exec: String,
-        host_unprocessed: Vec<u8>,
-    ) -> RouterInterjectionStatus {
-        match rule_type {
-            RouterRuleType::FakeDNS => {
-                let is_ipv6 = exec.contains(':') && !exec.contains('.');
-
-                let vec: Vec<u8> = if is_ipv6 {
-                    let addr = exec.parse::<std::net::Ipv6Addr>()
-                        .expect("Failed to parse requested IPv6 address. Make sure the address is an actual IPv6.");
-
-                    addr.octets().to_vec()
-                } else {
-                    exec.split('.').map(|n| {
-                        n.parse::<u8>()
-                            .expect("Failed to parse requested FakeDNS integer as u8. Make sure the address is an actual IPv4.")
-                    }).collect()
-                };
+    async fn matches_ip(rule_match: &str, ip: IpAddr, context: &RouteContext) -> Result<bool> {
+        let (statement, argument) = rule_match.split_once(' ').unwrap_or((rule_match, ""));
+
+        match statement {
+            /* A typo only costs the rule itself, not every connection reaching it */
+            "cidr" => match argument.trim().parse::<IpNetwork>() {
+                Ok(network) => Ok(network.contains(ip)),
+                Err(e) => {
+                    warn!("Skipping bad CIDR {argument} in pattern {rule_match}: {e}");
+
+                    Ok(false)
+                }
+            },
+            "if16kb" => {
+                let excluded = context
+                    .host()
+                    .is_some_and(|host| argument.split(',').any(|item| item.trim() == host));
+
+                Ok(!excluded && is_16kb_blocked(SocketAddr::new(ip, 443)).await)
+            }
+            _ => Ok(false),
+        }
+    }
 
-                RouterInterjectionStatus::AutoResolved(IpParser {
-                    host_raw: vec.clone(),
-                    host_unprocessed: host_unprocessed,
-                    port: 443,
-                    dest_addr_type: if is_ipv6 { 4 } else { 3 },
-                    is_udp: false,
+    async fn matches(rule: &RouterRule, context: &RouteContext) -> Result<bool> {
+        let rule_match = rule.rule_match.as_str();
+
+        let matched = match rule.scope {
+            RouterRuleScope::DnsQuery => context
+                .domain
+                .as_deref()
+                .is_some_and(|domain| domain_matcher::pattern(rule_match).matches(domain)),
+            RouterRuleScope::SNI => context
+                .sni
+                .as_deref()
+                .is_some_and(|sni| domain_matcher::pattern(rule_match).matches(sni)),
+            RouterRuleScope::IP => match context.ip {
+                Some(ip) => Self::matches_ip(rule_match, ip, context).await?,
+                None => false,
+            },
+            RouterRuleScope::Port => context
+                .port
+                .is_some_and(|port| matches_port(rule_match, port)),
+            RouterRuleScope::Protocol => context.protocol.as_ref().is_some_and(|protocol| {
+                format!("{protocol:?}").eq_ignore_ascii_case(rule_match.trim())
+            }),
+            RouterRuleScope::GeoIP => context.ip.is_some_and(|ip| {
+                geoip::lookup(GeoField::Country, ip).is_some_and(|country| {
+                    rule_match
+                        .split(',')
+                        .any(|item| item.trim().eq_ignore_ascii_case(&country))
                 })
+            }),
+            RouterRuleScope::ASN => context.ip.is_some_and(|ip| {
+                geoip::lookup(GeoField::Asn, ip)
+                    .is_some_and(|asn| rule_match.split(',').any(|item| matches_asn(item, &asn)))
+            }),
+            RouterRuleScope::User => context.identity.matches_user(rule_match),
+            RouterRuleScope::Listener => context.identity.matches_listener(rule_match),
+        };
+
+        Ok(matched)
+    }
+
+    /* Applies a matched rule to the decision collected so far */
+    fn apply(config: &AuxConfig, rule: &RouterRule, decision: &mut RouteDecision) -> RuleOutcome {
+        if rule.rule_type == RouterRuleType::FakeDNS {
+            let Ok(address) = rule.exec.trim().parse::<IpAddr>() else {
+                warn!(
+                    "Skipping FakeDNS rule {} with a bad address {}",
+                    rule.rule_match, rule.exec
+                );
+
+                return RuleOutcome::Skipped;
+            };
+
+            decision.address = Some(address);
+
+            return RuleOutcome::Routed;
+        }
+
+        let (action_type, exec) = rule.exec.split_once(' ').unwrap_or((&rule.exec, ""));
+
+        match action_type {
+            "ratelimit" => {
+                let Ok(rate) = exec.trim().parse::<u64>() else {
+                    warn!(
+                        "Skipping bad rate limit {exec} in pattern {}",
+                        &rule.rule_match
+                    );
+
+                    return RuleOutcome::Skipped;
+                };
+
+                decision
+                    .rate_limits
+                    .push((format!("rule {:?} {}", rule.scope, rule.rule_match), rate));
+
+                return RuleOutcome::Modified;
             }
+            "relay" => {
+                let Some(mode) = RelayMode::from_name(exec) else {
+                    info!(
+                        "Skipping unknown relay mode {exec} in pattern {}",
+                        &rule.rule_match
+                    );
+
+                    return RuleOutcome::Skipped;
+                };
 
-            type_ => {
-                error!("DNS Router doesn't support {type_:?}");
+                decision.relay_mode.get_or_insert(mode);
 
-                RouterInterjectionStatus::Allow
+                return RuleOutcome::Modified;
             }
+            _ => {}
         }
+
+        let action = match action_type {
+            "direct" if exec.is_empty() => RouteAction::Direct,
+            "direct" => {
+                if !config
+                    .strategy_profiles
+                    .profiles
+                    .iter()
+                    .any(|profile| profile.name == exec)
+                {
+                    info!(
+                        "Skipping unknown strategy profile {exec} in pattern {}",
+                        &rule.rule_match
+                    );
+
+                    return RuleOutcome::Skipped;
+                }
+
+                decision.profile = Some(exec.to_string());
+
+                RouteAction::Direct
+            }
+            "block" => RouteAction::Block,
+            "pool" => RouteAction::Pool(exec.to_string()),
+            _ => match UpstreamProtocol::from_action(action_type) {
+                Some((protocol, remote_dns)) => RouteAction::Proxy {
+                    protocol,
+                    remote_dns,
+                    proxy: exec.to_string(),
+                },
+                None => {
+                    info!(
+                        "Skipping bad action type for exec {} in pattern {}",
+                        &rule.exec, &rule.rule_match
+                    );
+
+                    return RuleOutcome::Skipped;
+                }
+            },
+        };
+
+        decision.action = action;
+
+        RuleOutcome::Routed
     }
 
-    pub async fn interject_dns<'a>(
-        ref config: AuxConfig,
-        ref buffer: impl AsRef<[u8]>,
-    ) -> Result<RouterInterjectionStatus> {
-        let rules = Self::query_router_rules(config, &RouterRuleType::FakeDNS);
+    /* Rules are tried in the order they were written. Ratelimit and relay
+     * rules add to the decision, the first routing rule completes it
+     */
+    pub async fn route(config: &AuxConfig, context: &RouteContext) -> Result<RouteDecision> {
+        let mut decision = RouteDecision::default();
 
+        for rule in &config.router_options.rules {
+            if Self::matches(rule, context).await?
+                && Self::apply(config, rule, &mut decision) == RuleOutcome::Routed
+            {
+                break;
+            }
+        }
+
+        Ok(decision)
+    }
+
+    /* Whether an SNI rule comes before the routing rule that would decide
+     * the connection now, so routing it has to wait for the ClientHello
+     */
+    pub async fn waits_for_sni(config: &AuxConfig, context: &RouteContext) -> Result<bool> {
+        if context.sni.is_some() {
+            return Ok(false);
+        }
+
+        let mut decision = RouteDecision::default();
+
+        for rule in &config.router_options.rules {
+            if rule.scope == RouterRuleScope::SNI {
+                return Ok(true);
+            }
+
+            if Self::matches(rule, context).await?
+                && Self::apply(config, rule, &mut decision) == RuleOutcome::Routed
+            {
+                return Ok(false);
+            }
+        }
+
+        Ok(false)
+    }
+
+    pub async fn interject_dns(
+        config: AuxConfig,
+        buffer: impl AsRef<[u8]>,
+        identity: &ClientIdentity,
+    ) -> Result<RouterInterjectionStatus> {
         let ip_parser_result = IpParser::parse_no_resolve(buffer.as_ref()).await?;
 
-        for rule in rules {
-            if rule.scope != RouterRuleScope::DnsQuery {
-                continue;
+        if ip_parser_result.dest_addr_type != 3 {
+            return Ok(RouterInterjectionStatus::Allow);
+        }
+
+        let context = RouteContext {
+            domain: Some(String::from_utf8_lossy(&ip_parser_result.host_unprocessed).into_owned()),
+            port: Some(ip_parser_result.port),
+            protocol: Some(if ip_parser_result.is_udp {
+                NetworkProtocol::UDP
+            } else {
+                NetworkProtocol::TCP
+            }),
+            identity: identity.clone(),
+            ..Default::default()
+        };
+
+        let Some(address) = Self::route(&config, &context).await?.address else {
+            return Ok(RouterInterjectionStatus::Allow);
+        };
+
+        let host_raw = match address {
+            IpAddr::V4(address) => address.octets().to_vec(),
+            IpAddr::V6(address) => address.octets().to_vec(),
+        };
+
+        /* Still a domain request, so replies keep the name the client asked for */
+        Ok(RouterInterjectionStatus::AutoResolved(IpParser {
+            host_raw,
+            host_unprocessed: ip_parser_result.host_unprocessed,
+            port: ip_parser_result.port,
+            dest_addr_type: 3,
+            is_udp: ip_parser_result.is_udp,
+            resolved: vec![],
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn rule(scope: RouterRuleScope, rule_match: &str, exec: &str) -> RouterRule {
+        RouterRule {
+            scope,
+            rule_type: RouterRuleType::Forward,
+            rule_match: rule_match.to_string(),
+            exec: exec.to_string(),
+        }
+    }
+
+    fn config(rules: Vec<RouterRule>) -> AuxConfig {
+        let mut config = AuxConfig::default();
+
+        config.router_options.rules = rules;
+
+        config
+    }
+
+    fn context(domain: &str, sni: Option<&str>, port: u16) -> RouteContext {
+        RouteContext {
+            domain: Some(domain.to_string()),
+            sni: sni.map(str::to_string),
+            port: Some(port),
+            protocol: Some(NetworkProtocol::TCP),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let config = config(vec![
+            rule(RouterRuleScope::Port, "443", "block"),
+            rule(
+                RouterRuleScope::DnsQuery,
+                "example.com",
+                "socks5 127.0.0.1:1080",
+            ),
+        ]);
+
+        let decision =
+            block_on(Router::route(&config, &context("example.com", None, 443))).unwrap();
+
+        assert_eq!(decision.action, RouteAction::Block);
+
+        let decision = block_on(Router::route(&config, &context("example.com", None, 80))).unwrap();
+
+        assert_eq!(
+            decision.action,
+            RouteAction::Proxy {
+                protocol: UpstreamProtocol::Socks5,
+                remote_dns: false,
+                proxy: "127.0.0.1:1080".to_string(),
             }
+        );
+    }
 
-            let pattern = match Pattern::new(&rule.rule_match) {
-                Ok(pattern) => pattern,
-                Err(_) => return Ok(RouterInterjectionStatus::Allow),
-            };
+    #[test]
+    fn modifiers_combine_with_the_route() {
+        let config = config(vec![
+            rule(RouterRuleScope::Port, "443", "ratelimit 1000"),
+            rule(RouterRuleScope::Protocol, "TCP", "relay threaded"),
+            rule(RouterRuleScope::Port, "80,443", "ratelimit 5000"),
+            rule(RouterRuleScope::Port, "443", "relay async"),
+            rule(RouterRuleScope::Port, "443", "socks5 127.0.0.1:1080"),
+            rule(RouterRuleScope::Port, "443", "ratelimit 10"),
+            rule(RouterRuleScope::Port, "80", "block"),
+        ]);
 
-            if pattern.matches(
-                String::from_utf8_lossy(&ip_parser_result.host_unprocessed)
-                    .into_owned()
-                    .as_ref(),
-            ) {
-                return Ok(Self::router_process(
-                    rule.rule_type.clone(),
-                    rule.exec.clone(),
-                    ip_parser_result.host_unprocessed,
-                ));
+        let decision =
+            block_on(Router::route(&config, &context("example.com", None, 443))).unwrap();
+
+        assert_eq!(
+            decision.action,
+            RouteAction::Proxy {
+                protocol: UpstreamProtocol::Socks5,
+                remote_dns: false,
+                proxy: "127.0.0.1:1080".to_string(),
             }
-        }
+        );
+        assert_eq!(
+            decision
+                .rate_limits
+                .iter()
+                .map(|(_, rate)| *rate)
+                .collect::<Vec<_>>(),
+            vec![1000, 5000]
+        );
+        assert_eq!(decision.relay_mode, Some(RelayMode::Threaded));
+
+        let decision = block_on(Router::route(&config, &context("example.com", None, 80))).unwrap();
+
+        assert_eq!(decision.action, RouteAction::Block);
+        assert_eq!(decision.rate_limits.len(), 1);
+        assert_eq!(decision.relay_mode, Some(RelayMode::Threaded));
+    }
+
+    #[test]
+    fn modifiers_alone_go_direct() {
+        let config = config(vec![rule(RouterRuleScope::Port, "443", "ratelimit 1000")]);
+
+        let decision =
+            block_on(Router::route(&config, &context("example.com", None, 443))).unwrap();
+
+        assert_eq!(decision.action, RouteAction::Direct);
+        assert_eq!(decision.rate_limits.len(), 1);
+    }
+
+    #[test]
+    fn fake_dns_ends_the_search() {
+        let mut fake_dns = rule(RouterRuleScope::DnsQuery, "example.com", "192.0.2.1");
+
+        fake_dns.rule_type = RouterRuleType::FakeDNS;
+
+        let config = config(vec![fake_dns, rule(RouterRuleScope::Port, "443", "block")]);
+
+        let decision =
+            block_on(Router::route(&config, &context("example.com", None, 443))).unwrap();
+
+        assert_eq!(decision.action, RouteAction::Direct);
+        assert_eq!(decision.address, Some("192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn skipped_rules_fall_through() {
+        let config = config(vec![
+            rule(RouterRuleScope::Port, "443", "relay sideways"),
+            rule(RouterRuleScope::Port, "443", "ratelimit fast"),
+            rule(RouterRuleScope::Port, "443", "direct missing-profile"),
+            rule(RouterRuleScope::Port, "443", "block"),
+        ]);
+
+        let decision =
+            block_on(Router::route(&config, &context("example.com", None, 443))).unwrap();
+
+        assert_eq!(decision.action, RouteAction::Block);
+    }
+
+    #[test]
+    fn bad_cidr_does_not_match() {
+        let config = config(vec![
+            rule(RouterRuleScope::IP, "cidr 10.0.0.0/33", "block"),
+            rule(
+                RouterRuleScope::IP,
+                "cidr 10.0.0.0/8",
+                "socks5 127.0.0.1:1080",
+            ),
+        ]);
+
+        let route = RouteContext {
+            ip: Some("10.1.2.3".parse().unwrap()),
+            ..context("example.com", None, 443)
+        };
+
+        assert!(matches!(
+            block_on(Router::route(&config, &route)).unwrap().action,
+            RouteAction::Proxy { .. }
+        ));
+    }
+
+    #[test]
+    fn users_and_listeners_match_entries() {
+        let config = config(vec![
+            rule(RouterRuleScope::User, "dev-*", "block"),
+            rule(RouterRuleScope::Listener, "lan", "socks5 127.0.0.1:1080"),
+        ]);
+
+        let route = |user: &str, listener: &str| RouteContext {
+            identity: ClientIdentity {
+                user: Some(user.to_string()),
+                listener: Some(listener.to_string()),
+                client_ip: None,
+            },
+            ..context("example.com", None, 443)
+        };
+
+        let action = |user, listener| {
+            block_on(Router::route(&config, &route(user, listener)))
+                .unwrap()
+                .action
+        };
+
+        assert_eq!(action("dev-alice", "lan"), RouteAction::Block);
+        assert!(matches!(action("alice", "lan"), RouteAction::Proxy { .. }));
+        assert_eq!(action("alice", "lan-guest"), RouteAction::Direct);
+    }
+
+    #[test]
+    fn unknown_sni_does_not_match() {
+        let config = config(vec![
+            rule(RouterRuleScope::SNI, "example.com", "block"),
+            rule(RouterRuleScope::Port, "443", "socks5 127.0.0.1:1080"),
+        ]);
+
+        let unknown = context("example.com", None, 443);
+        let known = context("example.com", Some("example.com"), 443);
+
+        assert!(matches!(
+            block_on(Router::route(&config, &unknown)).unwrap().action,
+            RouteAction::Proxy { .. }
+        ));
+        assert_eq!(
+            block_on(Router::route(&config, &known)).unwrap().action,
+            RouteAction::Block
+        );
+    }
+
+    #[test]
+    fn waits_for_sni_only_before_the_routing_rule() {
+        let sni_first = config(vec![
+            rule(RouterRuleScope::SNI, "example.com", "block"),
+            rule(RouterRuleScope::Port, "443", "socks5 127.0.0.1:1080"),
+        ]);
+
+        let sni_last = config(vec![
+            rule(RouterRuleScope::Port, "443", "socks5 127.0.0.1:1080"),
+            rule(RouterRuleScope::SNI, "example.com", "block"),
+        ]);
+
+        let modifier_first = config(vec![
+            rule(RouterRuleScope::Port, "443", "ratelimit 1000"),
+            rule(RouterRuleScope::SNI, "example.com", "block"),
+            rule(RouterRuleScope::Port, "443", "socks5 127.0.0.1:1080"),
+        ]);
+
+        let unknown = context("example.com", None, 443);
+        let known = context("example.com", Some("example.com"), 443);
 
-        Ok(RouterInterjectionStatus::Allow)
+        assert!(block_on(Router::waits_for_sni(&sni_first, &unknown)).unwrap());
+        assert!(block_on(Router::waits_for_sni(&modifier_first, &unknown)).unwrap());
+        assert!(!block_on(Router::waits_for_sni(&sni_first, &known)).unwrap());
+        assert!(!block_on(Router::waits_for_sni(&sni_last, &unknown)).unwrap());
     }
 }
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, LazyLock, Mutex, Weak};
+use std::time::Duration;
+
+use tokio::time::Instant;
+use wfconfig::aux_config::AuxConfig;
+
+/* Tokens may go into debt, so a large read is let through at once and the
+ * connection then waits until the bucket has paid it off. The capacity is
+ * one second worth of traffic
+ */
+pub struct TokenBucket {
+    rate: u64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> TokenBucket {
+        TokenBucket {
+            rate,
+            state: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    fn reserve(&self, bytes: usize, now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+
+        let (ref mut tokens, ref mut refilled) = *state;
+
+        let rate = self.rate as f64;
+
+        *tokens = (*tokens + now.duration_since(*refilled).as_secs_f64() * rate).min(rate);
+        *refilled = now;
+
+        *tokens -= bytes as f64;
+
+        if *tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+
+        Duration::from_secs_f64(-*tokens / rate)
+    }
+}
+
+type BucketKey = (String, u64);
+
+/* Buckets are shared by every connection with the same key and rate, and
+ * go away with the last connection using them
+ */
+static BUCKETS: LazyLock<Mutex<HashMap<BucketKey, Weak<TokenBucket>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn bucket(key: String, rate: u64) -> Arc<TokenBucket> {
+    let mut buckets = BUCKETS.lock().unwrap();
+
+    if let Some(bucket) = buckets.get(&(key.clone(), rate)).and_then(Weak::upgrade) {
+        return bucket;
+    }
+
+    buckets.retain(|_, bucket| bucket.strong_count() > 0);
+
+    let bucket = Arc::new(TokenBucket::new(rate));
+
+    buckets.insert((key, rate), Arc::downgrade(&bucket));
+
+    bucket
+}
+
+#[derive(Default)]
+pub struct Shaper {
+    buckets: Vec<Arc<TokenBucket>>,
+}
+
+impl Shaper {
+    pub fn for_client(config: &AuxConfig, client_ip: Option<IpAddr>) -> Shaper {
+        let options = &config.shaping_options;
+
+        let mut shaper = Shaper::default();
+
+        if options.global_rate != 0 {
+            shaper.add("global".to_string(), options.global_rate);
+        }
+
+        if let Some(ip) = client_ip.filter(|_| options.client_rate != 0) {
+            shaper.add(format!("client {}", ip.to_canonical()), options.client_rate);
+        }
+
+        shaper
+    }
+
+    pub fn add(&mut self, key: String, rate: u64) {
+        if rate != 0 {
+            self.buckets.push(bucket(key, rate));
+        }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    /* Charges every bucket, the slowest one decides how long to wait */
+    fn reserve(&self, bytes: usize) -> Duration {
+        let now = Instant::now();
+
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.reserve(bytes, now))
+            .max()
+            .unwrap_or_default()
+    }
+
+    pub async fn throttle(&self, bytes: usize) {
+        let wait = self.reserve(bytes);
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    pub fn throttle_blocking(&self, bytes: usize) {
+        let wait = self.reserve(bytes);
+
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u64 = 1024;
+
+    fn millis(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn starts_with_a_second_of_burst() {
+        let start = Instant::now();
+        let bucket = TokenBucket::new(RATE);
+
+        assert_eq!(bucket.reserve(1024, start), Duration::ZERO);
+        assert_eq!(bucket.reserve(256, start), millis(250));
+    }
+
+    #[test]
+    fn refills_at_the_rate() {
+        let start = Instant::now();
+        let bucket = TokenBucket::new(RATE);
+
+        assert_eq!(bucket.reserve(1024, start), Duration::ZERO);
+
+        assert_eq!(bucket.reserve(256, start + millis(250)), Duration::ZERO);
+        assert_eq!(bucket.reserve(512, start + millis(750)), Duration::ZERO);
+        assert_eq!(bucket.reserve(256, start + millis(750)), millis(250));
+    }
+
+    #[test]
+    fn refills_no_more_than_the_capacity() {
+        let start = Instant::now();
+        let bucket = TokenBucket::new(RATE);
+
+        assert_eq!(bucket.reserve(1024, start), Duration::ZERO);
+        assert_eq!(bucket.reserve(1536, start + millis(10000)), millis(500));
+    }
+
+    #[test]
+    fn lets_a_large_read_through_and_pays_off_the_debt() {
+        let start = Instant::now();
+        let bucket = TokenBucket::new(RATE);
+
+        /* Two seconds worth of traffic over the capacity */
+        assert_eq!(bucket.reserve(3072, start), millis(2000));
+
+        /* Everything in between keeps waiting for the debt */
+        assert_eq!(bucket.reserve(0, start + millis(1000)), millis(1000));
+        assert_eq!(bucket.reserve(256, start + millis(1500)), millis(750));
+        assert_eq!(bucket.reserve(0, start + millis(2250)), Duration::ZERO);
+    }
+
+    #[test]
+    fn slowest_bucket_decides() {
+        let mut shaper = Shaper::default();
+
+        assert!(shaper.is_unlimited());
+
+        shaper.add("test slowest fast".to_string(), 1 << 20);
+        shaper.add("test slowest slow".to_string(), RATE);
+        shaper.add("test slowest unlimited".to_string(), 0);
+
+        assert_eq!(shaper.buckets.len(), 2);
+
+        let wait = shaper.reserve(2048);
+
+        assert!(wait > millis(900) && wait <= millis(1000), "{wait:?}");
+    }
+
+    #[test]
+    fn buckets_are_shared_by_key_and_rate() {
+        let mut first = Shaper::default();
+        let mut second = Shaper::default();
+        let mut other_rate = Shaper::default();
+
+        first.add("test shared".to_string(), RATE);
+        second.add("test shared".to_string(), RATE);
+        other_rate.add("test shared".to_string(), RATE * 2);
+
+        assert_eq!(first.reserve(1024), Duration::ZERO);
+        assert!(second.reserve(1024) > millis(900));
+        assert_eq!(other_rate.reserve(1024), Duration::ZERO);
+    }
+
+    #[test]
+    fn buckets_go_away_with_their_last_user() {
+        let mut shaper = Shaper::default();
+
+        shaper.add("test dropped".to_string(), RATE);
+
+        assert_eq!(shaper.reserve(1024), Duration::ZERO);
+
+        drop(shaper);
+
+        let mut shaper = Shaper::default();
+
+        shaper.add("test dropped".to_string(), RATE);
+
+        assert_eq!(shaper.reserve(1024), Duration::ZERO);
+    }
+}
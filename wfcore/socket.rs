@@ -1,81 +1,157 @@
-use anyhow::{anyhow, Result};
-use ipnetwork::IpNetwork;
-use std::io::Write;
-use std::net::ToSocketAddrs;
-use std::net::{IpAddr, SocketAddr};
-use tokio::net::{TcpSocket, TcpStream};
-
-use crate::router::Router;
-use std::io::Read;
-use wfconfig::aux_config::{RouterRuleScope, RouterRuleType, SocketOptions};
-use wfconfig::parse_args;
-
-use log::info;
-use wfblmark::is_16kb_blocked;
+use anyhow::Result;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+
+use crate::relay::RelayPolicy;
+use crate::router::{RouteAction, RouteContext, Router};
+use crate::shaping::Shaper;
+use crate::upstream::{pool, UpstreamProtocol, UpstreamProxy, UpstreamTarget, UpstreamUnreachable};
+use wfconfig::aux_config::{AuxConfig, SocketOptions};
+use wfconfig::{parse_args, NetworkProtocol};
+
+use log::{info, warn};
+use socket2::{SockRef, TcpKeepalive};
 
 pub struct SocketOps();
 
-fn checkis16kbaccepted(conditional: &str, sni: String) -> bool {
-    let mut split = conditional.splitn(2, ' ');
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+#[derive(Debug)]
+pub struct BlockedByRouter;
 
-    let opt_action_type = split.next();
-    let opt_exec = split.next();
+impl std::fmt::Display for BlockedByRouter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Connection aborted by a router rule")
+    }
+}
 
-    if opt_action_type.is_some() && opt_exec.is_some() {
-        let vsplit = opt_exec.unwrap().split(',').collect::<Vec<_>>();
+impl std::error::Error for BlockedByRouter {}
 
-        return !vsplit.contains(&sni.as_str());
+/* Alternates the address families while keeping the order within each
+ * family, starting with whatever family the resolver preferred
+ */
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
     };
 
-    opt_action_type.is_some_and(|x| x == "if16kb")
-}
+    let prefer_ipv6 = first.is_ipv6();
+
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == prefer_ipv6);
+
+    let mut result = vec![];
 
-fn checkiscidraccepted(conditional: &str) -> bool {
-    let mut split = conditional.splitn(2, ' ');
+    preferred.reverse();
+    other.reverse();
 
-    let opt_action_type = split.next();
+    while let Some(addr) = preferred.pop() {
+        result.push(addr);
 
-    opt_action_type.is_some_and(|x| x == "cidr")
+        if let Some(addr) = other.pop() {
+            result.push(addr);
+        }
+    }
+
+    other.reverse();
+    result.extend(other);
+
+    result
 }
 
 impl SocketOps {
-    pub fn new_proxied(addr: SocketAddr, proxy0: String) -> Result<TcpStream> {
-        info!("{addr:?} is being forwarded to proxy {proxy0}");
+    pub async fn new_proxied(
+        host: &str,
+        addrs: &[SocketAddr],
+        proxy0: &str,
+        protocol: UpstreamProtocol,
+        remote_dns: bool,
+    ) -> Result<TcpStream> {
+        let proxy = UpstreamProxy::parse(proxy0)?;
+
+        let addr = *addrs
+            .first()
+            .ok_or(anyhow::anyhow!("No addresses for {host}"))?;
+
+        /* SOCKS4 only knows IPv4, so a dual-stack host goes by its IPv4
+         * address and an IPv6-only one by name in the SOCKS4a form
+         */
+        let (addr, remote_dns) = match protocol {
+            UpstreamProtocol::Socks4 => match addrs.iter().find(|addr| addr.is_ipv4()) {
+                Some(addr) => (*addr, remote_dns),
+                None => (addr, remote_dns || addr.is_ipv6()),
+            },
+            _ => (addr, remote_dns),
+        };
 
-        let mut socket = std::net::TcpStream::connect(
-            proxy0
-                .to_socket_addrs()?
-                .next()
-                .ok_or(anyhow!("No addrs"))?,
-        )?;
+        let target = UpstreamTarget::new(host, addr, remote_dns);
 
-        let mut buf = [0u8; 512];
+        info!(
+            "{target} is being forwarded to {protocol:?} proxy {}:{}",
+            proxy.host, proxy.port
+        );
 
-        socket.write_all(&[0x05, 0x01, 0x00])?;
+        let timeout = SocketOptions::timeout(parse_args().socket_options.connect_timeout);
 
-        socket.read(&mut buf)?;
+        match tokio::time::timeout(timeout, proxy.open(protocol, &target)).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow::Error::new(std::io::Error::new(
+                ErrorKind::TimedOut,
+                format!("Upstream proxy {}:{} has timed out", proxy.host, proxy.port),
+            ))
+            .context(proxy.unreachable())),
+        }
+    }
 
-        let mut connreq = Vec::with_capacity(22);
+    /* Tries pool members one by one, only an unreachable upstream is marked
+     * down, since a refusal to reach the target says nothing about its health
+     */
+    pub async fn connect_pool(
+        host: &str,
+        addrs: &[SocketAddr],
+        name: &str,
+    ) -> Result<(TcpStream, pool::Lease)> {
+        let config = parse_args();
 
-        connreq.extend_from_slice(&[0x05, 0x01, 0x00]);
+        let pool = config
+            .upstream_options
+            .pools
+            .iter()
+            .find(|pool| pool.name == name)
+            .ok_or(anyhow::anyhow!("No upstream pool named {name}"))?;
 
-        match addr {
-            SocketAddr::V4(v4) => {
-                connreq.push(0x01);
-                connreq.extend_from_slice(&v4.ip().octets());
-                connreq.extend_from_slice(&v4.port().to_be_bytes());
-            }
-            SocketAddr::V6(v6) => {
-                connreq.push(0x04);
-                connreq.extend_from_slice(&v6.ip().octets());
-                connreq.extend_from_slice(&v6.port().to_be_bytes());
+        let mut last_error = anyhow::anyhow!("Upstream pool {name} has no members");
+
+        for member in pool::ordered_members(pool) {
+            let Some((action_type, spec)) = member.exec.split_once(' ') else {
+                continue;
+            };
+
+            let Some((protocol, remote_dns)) = UpstreamProtocol::from_action(action_type) else {
+                info!("Skipping bad pool member {} in pool {name}", member.exec);
+
+                continue;
+            };
+
+            match Self::new_proxied(host, addrs, spec, protocol, remote_dns).await {
+                Ok(stream) => return Ok((stream, pool::lease(&member.exec))),
+                Err(error) => {
+                    if error.downcast_ref::<UpstreamUnreachable>().is_some() {
+                        pool::mark_down(&member.exec);
+                    }
+
+                    warn!("Pool {name} member has failed: {error}, trying the next one");
+
+                    last_error = error;
+                }
             }
         }
 
-        socket.write_all(&connreq)?;
-        socket.read(&mut buf)?;
-
-        Ok(TcpStream::from_std(socket)?)
+        Err(last_error)
     }
 
     #[cfg(target_os = "windows")]
@@ -85,57 +161,14 @@ impl SocketOps {
         adapters
             .iter()
             .find(|a| a.friendly_name() == &ifname)
-            .ok_or(anyhow!("Adapter not found"))?
+            .ok_or(anyhow::anyhow!("Adapter not found"))?
             .ip_addresses()
             .first()
-            .ok_or(anyhow!("No IP for adapter {ifname}"))
+            .ok_or(anyhow::anyhow!("No IP for adapter {ifname}"))
             .copied()
     }
 
-    pub async fn connect_socket(sni: String, addr: SocketAddr) -> Result<TcpStream> {
-        let config = parse_args();
-
-        let rules = Router::query_router_rules(&config, &RouterRuleType::Forward);
-
-        for rule in rules {
-            if rule.scope != RouterRuleScope::IP {
-                continue;
-            }
-
-            let ip = addr.ip();
-
-            let should_route = if checkis16kbaccepted(&rule.rule_match, sni.clone()) {
-                is_16kb_blocked(SocketAddr::new(ip, 443)).await
-            } else if checkiscidraccepted(&rule.rule_match) {
-                let network = rule.rule_match.parse::<IpNetwork>()?;
-
-                network.contains(ip)
-            } else {
-                false
-            };
-
-            if should_route {
-                let mut split = rule.exec.splitn(2, ' ');
-
-                if let (Some(action_type), Some(exec)) = (split.next(), split.next()) {
-                    match action_type {
-                        "socks5" => return SocketOps::new_proxied(addr, exec.to_string()),
-                        "block" => {
-                            return Err(anyhow!("Connection aborted by a router rule"));
-                        }
-                        _ => {
-                            info!(
-                                "Skipping bad action type for exec {split:?} in pattern {}",
-                                &rule.rule_match
-                            );
-
-                            continue;
-                        }
-                    }
-                }
-            }
-        }
-
+    pub fn new_bound_socket(addr: &SocketAddr) -> Result<TcpSocket> {
         let bind_options = parse_args().bind_options;
 
         let (tsocket, device_name) = match addr {
@@ -177,9 +210,175 @@ impl SocketOps {
             tsocket.bind(addr)?;
         }
 
+        Ok(tsocket)
+    }
+
+    /* The address reported to the client is the one the kernel would
+     * use to reach the expected peer, not the wildcard we listen on
+     */
+    pub async fn bind_listener(peer: SocketAddr) -> Result<(TcpListener, SocketAddr)> {
+        let tsocket = Self::new_bound_socket(&peer)?;
+
+        let unspecified: IpAddr = match peer {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+
+        #[cfg(not(target_os = "windows"))]
+        tsocket.bind(SocketAddr::new(unspecified, 0))?;
+
+        let listener = tsocket.listen(1)?;
+
+        let local_addr = listener.local_addr()?;
+
+        if peer.ip().is_unspecified() {
+            return Ok((listener, local_addr));
+        }
+
+        let probe = UdpSocket::bind(SocketAddr::new(unspecified, 0)).await?;
+
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        {
+            let bind_options = parse_args().bind_options;
+
+            let device_name = match peer {
+                SocketAddr::V4(_) => bind_options.iface_ipv4,
+                SocketAddr::V6(_) => bind_options.iface_ipv6,
+            };
+
+            if &device_name != "default" {
+                probe.bind_device(Some(device_name.as_bytes()))?;
+            }
+        }
+
+        probe.connect(peer).await?;
+
+        Ok((
+            listener,
+            SocketAddr::new(probe.local_addr()?.ip(), local_addr.port()),
+        ))
+    }
+
+    /* The first address is the preferred one and picks the route. Rules may
+     * tell the addresses of a host apart (IP, GeoIP, ASN), so only the ones
+     * routed the same way stay in the race.
+     * The policy has to stay with the stream until the relay is done with it
+     */
+    pub async fn connect_socket(
+        mut route: RouteContext,
+        addrs: &[SocketAddr],
+    ) -> Result<(TcpStream, RelayPolicy)> {
+        let config = parse_args();
+
+        let addr = *addrs.first().ok_or(anyhow::anyhow!(
+            "No addresses for {}",
+            route.host().unwrap_or_default()
+        ))?;
+
+        /* An unresolved domain has no address to match yet */
+        route.ip = Some(addr.ip()).filter(|ip| !ip.is_unspecified());
+        route.port = Some(addr.port());
+        route.protocol.get_or_insert(NetworkProtocol::TCP);
+
+        let host = route
+            .host()
+            .map(str::to_string)
+            .unwrap_or_else(|| addr.ip().to_string());
+
+        let decision = Router::route(&config, &route).await?;
+
+        let mut candidates = vec![addr];
+
+        for candidate in &addrs[1..] {
+            let candidate_route = RouteContext {
+                ip: Some(candidate.ip()).filter(|ip| !ip.is_unspecified()),
+                ..route.clone()
+            };
+
+            if Router::route(&config, &candidate_route).await? == decision {
+                candidates.push(*candidate);
+            } else {
+                info!("{candidate} of {host} is routed differently, leaving it out");
+            }
+        }
+
+        let addrs = &candidates[..];
+
+        let mut policy = RelayPolicy {
+            shaper: Shaper::for_client(&config, route.identity.client_ip),
+            mode: decision.relay_mode,
+            profile: decision.profile,
+            lease: None,
+        };
+
+        for (key, rate) in decision.rate_limits {
+            policy.shaper.add(key, rate);
+        }
+
+        let stream = match decision.action {
+            RouteAction::Block => return Err(BlockedByRouter.into()),
+            RouteAction::Pool(ref name) => {
+                let (stream, lease) = Self::connect_pool(&host, addrs, name).await?;
+
+                policy.lease = Some(lease);
+
+                stream
+            }
+            RouteAction::Proxy {
+                protocol,
+                remote_dns,
+                ref proxy,
+            } => Self::new_proxied(&host, addrs, proxy, protocol, remote_dns).await?,
+            RouteAction::Direct => Self::connect_any(&host, addrs, &config).await?,
+        };
+
+        Ok((stream, policy))
+    }
+
+    async fn connect_any(
+        host: &str,
+        addrs: &[SocketAddr],
+        config: &AuxConfig,
+    ) -> Result<TcpStream> {
+        let candidates: Vec<SocketAddr> = addrs
+            .iter()
+            .filter(|addr| !addr.ip().is_unspecified())
+            .copied()
+            .collect();
+
+        if candidates.is_empty() {
+            return Err(std::io::Error::new(
+                ErrorKind::HostUnreachable,
+                format!("Could not resolve {host}"),
+            )
+            .into());
+        }
+
+        let timeout = SocketOptions::timeout(config.socket_options.connect_timeout);
+
+        match tokio::time::timeout(
+            timeout,
+            Self::happy_eyeballs(interleave_families(candidates)),
+        )
+        .await
+        {
+            Ok(result) => result,
+            Err(_) => Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                format!("Connection to {host} has timed out"),
+            )
+            .into()),
+        }
+    }
+
+    async fn connect_direct(addr: SocketAddr) -> Result<TcpStream> {
+        let tsocket = Self::new_bound_socket(&addr)?;
+
         let SocketOptions {
             so_recv_size,
             so_send_size,
+            keepalive_interval,
+            keepalive_count,
             ..
         } = parse_args().socket_options;
 
@@ -190,6 +389,178 @@ impl SocketOps {
 
         let stream = tsocket.connect(addr).await?;
 
+        Self::tune_keepalive(&stream, keepalive_interval, keepalive_count)?;
+
         Ok(stream)
     }
+
+    /* The first probe goes out after one interval of silence, so a dead peer
+     * is detected after interval * (count + 1) at most
+     */
+    pub(crate) fn tune_keepalive(stream: &TcpStream, interval: u64, count: u32) -> Result<()> {
+        if interval == 0 {
+            return Ok(());
+        }
+
+        let interval = Duration::from_millis(interval);
+
+        let keepalive = TcpKeepalive::new().with_time(interval);
+
+        #[cfg(any(
+            target_os = "android",
+            target_os = "fuchsia",
+            target_os = "linux",
+            target_os = "ios",
+            target_os = "macos",
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "windows"
+        ))]
+        let keepalive = keepalive.with_interval(interval).with_retries(count);
+
+        #[cfg(not(any(
+            target_os = "android",
+            target_os = "fuchsia",
+            target_os = "linux",
+            target_os = "ios",
+            target_os = "macos",
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "windows"
+        )))]
+        let _ = count;
+
+        SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+
+        Ok(())
+    }
+
+    /* RFC 8305: a new attempt starts every CONNECTION_ATTEMPT_DELAY or as soon
+     * as the previous one fails, the first established connection wins and
+     * the attempts still in flight are dropped with it
+     */
+    async fn happy_eyeballs(addrs: Vec<SocketAddr>) -> Result<TcpStream> {
+        let mut pending = addrs.into_iter();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_error = None;
+
+        loop {
+            if attempts.is_empty() {
+                match pending.next() {
+                    Some(addr) => attempts.push(Self::connect_direct(addr)),
+                    None => {
+                        return Err(last_error.unwrap_or(anyhow::anyhow!("No addresses to connect")))
+                    }
+                }
+            }
+
+            tokio::select! {
+                Some(result) = attempts.next() => match result {
+                    Ok(stream) => return Ok(stream),
+                    Err(error) => {
+                        warn!("Connection attempt has failed: {error}");
+
+                        if let Some(addr) = pending.next() {
+                            attempts.push(Self::connect_direct(addr));
+                        }
+
+                        last_error = Some(error);
+                    }
+                },
+                _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if pending.len() != 0 => {
+                    if let Some(addr) = pending.next() {
+                        attempts.push(Self::connect_direct(addr));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn alternates_starting_with_the_preferred_family() {
+        let cases = [
+            (
+                &[
+                    "[2001:db8::1]:443",
+                    "[2001:db8::2]:443",
+                    "192.0.2.1:443",
+                    "192.0.2.2:443",
+                ][..],
+                &[
+                    "[2001:db8::1]:443",
+                    "192.0.2.1:443",
+                    "[2001:db8::2]:443",
+                    "192.0.2.2:443",
+                ][..],
+            ),
+            (
+                &[
+                    "192.0.2.1:443",
+                    "[2001:db8::1]:443",
+                    "192.0.2.2:443",
+                    "[2001:db8::2]:443",
+                ],
+                &[
+                    "192.0.2.1:443",
+                    "[2001:db8::1]:443",
+                    "192.0.2.2:443",
+                    "[2001:db8::2]:443",
+                ],
+            ),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(interleave_families(addrs(input)), addrs(expected));
+        }
+    }
+
+    #[test]
+    fn leftovers_of_either_family_go_last_in_order() {
+        assert_eq!(
+            interleave_families(addrs(&[
+                "192.0.2.1:443",
+                "192.0.2.2:443",
+                "192.0.2.3:443",
+                "[2001:db8::1]:443",
+            ])),
+            addrs(&[
+                "192.0.2.1:443",
+                "[2001:db8::1]:443",
+                "192.0.2.2:443",
+                "192.0.2.3:443",
+            ])
+        );
+
+        assert_eq!(
+            interleave_families(addrs(&[
+                "[2001:db8::1]:443",
+                "192.0.2.1:443",
+                "192.0.2.2:443",
+                "192.0.2.3:443",
+            ])),
+            addrs(&[
+                "[2001:db8::1]:443",
+                "192.0.2.1:443",
+                "192.0.2.2:443",
+                "192.0.2.3:443",
+            ])
+        );
+    }
+
+    #[test]
+    fn single_family_and_empty_lists_are_kept() {
+        let single = addrs(&["192.0.2.2:443", "192.0.2.1:443", "192.0.2.3:443"]);
+
+        assert_eq!(interleave_families(single.clone()), single);
+        assert_eq!(interleave_families(vec![]), vec![]);
+    }
 }
@@ -0,0 +1,71 @@
+use std::io::ErrorKind;
+
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::{UpstreamProxy, UpstreamTarget};
+
+const MAX_RESPONSE_HEAD: usize = 16 * 1024;
+
+fn status_error(status: u16) -> anyhow::Error {
+    let kind = match status {
+        403 | 407 => ErrorKind::PermissionDenied,
+        502 | 503 => ErrorKind::ConnectionRefused,
+        504 => ErrorKind::TimedOut,
+        _ => ErrorKind::Other,
+    };
+
+    std::io::Error::new(kind, format!("Upstream HTTP proxy has replied {status}")).into()
+}
+
+/* Reads byte by byte, whatever follows the head belongs to the tunnel */
+async fn read_response_head(socket: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut head = Vec::with_capacity(256);
+
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() == MAX_RESPONSE_HEAD {
+            return Err(anyhow!(
+                "Upstream HTTP response head is longer than {MAX_RESPONSE_HEAD} bytes"
+            ));
+        }
+
+        head.push(socket.read_u8().await?);
+    }
+
+    Ok(head)
+}
+
+pub async fn connect(proxy: &UpstreamProxy, target: &UpstreamTarget) -> Result<TcpStream> {
+    let mut socket = proxy.connect().await?;
+
+    let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+
+    if let Some((username, password)) = &proxy.credentials {
+        let token =
+            base64::engine::general_purpose::STANDARD.encode(format!("{username}:{password}"));
+
+        request.push_str(&format!("Proxy-Authorization: Basic {token}\r\n"));
+    }
+
+    request.push_str("\r\n");
+
+    socket.write_all(request.as_bytes()).await?;
+
+    let head = read_response_head(&mut socket).await?;
+
+    let status_line = String::from_utf8_lossy(&head);
+
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or(anyhow!("Bad upstream HTTP status line"))?;
+
+    if !(200..300).contains(&status) {
+        return Err(status_error(status));
+    }
+
+    Ok(socket)
+}
@@ -0,0 +1,167 @@
+pub mod http;
+pub mod pool;
+pub mod socks4;
+pub mod socks5;
+
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{anyhow, Context, Result};
+use tokio::net::TcpStream;
+
+use crate::socket::SocketOps;
+use wfconfig::parse_args;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UpstreamProtocol {
+    Socks5,
+    Socks4,
+    Http,
+}
+
+impl UpstreamProtocol {
+    /* Returns the protocol of a router action and whether it asks for remote DNS */
+    pub fn from_action(action_type: &str) -> Option<(UpstreamProtocol, bool)> {
+        match action_type {
+            "socks5" => Some((UpstreamProtocol::Socks5, false)),
+            "socks5h" => Some((UpstreamProtocol::Socks5, true)),
+            "socks4" => Some((UpstreamProtocol::Socks4, false)),
+            "http" => Some((UpstreamProtocol::Http, false)),
+            _ => None,
+        }
+    }
+}
+
+/* The upstream itself could not be reached, as opposed to it refusing the target */
+#[derive(Debug)]
+pub struct UpstreamUnreachable {
+    pub host: String,
+    pub port: u16,
+}
+
+impl std::fmt::Display for UpstreamUnreachable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Upstream proxy {}:{} is unreachable",
+            self.host, self.port
+        )
+    }
+}
+
+impl std::error::Error for UpstreamUnreachable {}
+
+/* An upstream proxy from a router rule, written as [user:pass@]host:port */
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpstreamProxy {
+    pub host: String,
+    pub port: u16,
+    pub credentials: Option<(String, String)>,
+}
+
+impl UpstreamProxy {
+    pub fn parse(spec: &str) -> Result<UpstreamProxy> {
+        let (credentials, address) = match spec.rsplit_once('@') {
+            Some((userinfo, address)) => {
+                let (username, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+
+                (Some((username.to_string(), password.to_string())), address)
+            }
+            None => (None, spec),
+        };
+
+        let (host, port) = address
+            .rsplit_once(':')
+            .ok_or(anyhow!("No port in upstream proxy {address}"))?;
+
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        if host.is_empty() {
+            return Err(anyhow!("No host in upstream proxy {address}"));
+        }
+
+        Ok(UpstreamProxy {
+            host: host.to_string(),
+            port: port.parse()?,
+            credentials,
+        })
+    }
+
+    pub async fn open(
+        &self,
+        protocol: UpstreamProtocol,
+        target: &UpstreamTarget,
+    ) -> Result<TcpStream> {
+        match protocol {
+            UpstreamProtocol::Socks5 => socks5::connect(self, target).await,
+            UpstreamProtocol::Socks4 => socks4::connect(self, target).await,
+            UpstreamProtocol::Http => http::connect(self, target).await,
+        }
+    }
+
+    pub fn unreachable(&self) -> UpstreamUnreachable {
+        UpstreamUnreachable {
+            host: self.host.clone(),
+            port: self.port,
+        }
+    }
+
+    pub async fn connect(&self) -> Result<TcpStream> {
+        let addr = tokio::net::lookup_host((self.host.as_str(), self.port))
+            .await
+            .with_context(|| self.unreachable())?
+            .next()
+            .ok_or(anyhow!("No addrs for upstream proxy {}", self.host))
+            .with_context(|| self.unreachable())?;
+
+        let tsocket = SocketOps::new_bound_socket(&addr)?;
+
+        tsocket.set_nodelay(true)?;
+        tsocket.set_keepalive(true)?;
+
+        let stream = tsocket
+            .connect(addr)
+            .await
+            .with_context(|| self.unreachable())?;
+
+        let options = parse_args().socket_options;
+
+        SocketOps::tune_keepalive(&stream, options.keepalive_interval, options.keepalive_count)?;
+
+        Ok(stream)
+    }
+}
+
+/* What the upstream is asked to connect to. A domain is forwarded when
+ * asked to or when we could not resolve it, so the upstream resolves it
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum UpstreamTarget {
+    Addr(SocketAddr),
+    Domain(String, u16),
+}
+
+impl UpstreamTarget {
+    pub fn new(host: &str, addr: SocketAddr, remote_dns: bool) -> UpstreamTarget {
+        let is_domain = !host.is_empty()
+            && host.len() <= 255
+            && host.parse::<IpAddr>().is_err()
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_');
+
+        if is_domain && (remote_dns || addr.ip().is_unspecified()) {
+            UpstreamTarget::Domain(host.to_string(), addr.port())
+        } else {
+            UpstreamTarget::Addr(addr)
+        }
+    }
+}
+
+impl std::fmt::Display for UpstreamTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpstreamTarget::Addr(addr) => write!(f, "{addr}"),
+            UpstreamTarget::Domain(domain, port) => write!(f, "{domain}:{port}"),
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+
+use log::{info, warn};
+use wfconfig::aux_config::{PoolStrategy, UpstreamPool, UpstreamPoolMember};
+use wfconfig::parse_args;
+
+use super::UpstreamProxy;
+
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/* How often a disabled health check looks whether a reload has enabled it */
+const HEALTH_CHECK_DISABLED_POLL: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct MemberState {
+    down: AtomicBool,
+    active: Arc<AtomicUsize>,
+}
+
+/* Member state is keyed by exec, so it survives config reloads */
+static MEMBERS: LazyLock<Mutex<HashMap<String, Arc<MemberState>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+static ROUND_ROBIN: LazyLock<Mutex<HashMap<String, usize>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn member_state(exec: &str) -> Arc<MemberState> {
+    MEMBERS
+        .lock()
+        .unwrap()
+        .entry(exec.to_string())
+        .or_default()
+        .clone()
+}
+
+/* Counts one connection through a pool member until dropped */
+pub struct Lease {
+    active: Arc<AtomicUsize>,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub fn lease(exec: &str) -> Lease {
+    let active = member_state(exec).active.clone();
+
+    active.fetch_add(1, Ordering::SeqCst);
+
+    Lease { active }
+}
+
+pub fn mark_down(exec: &str) {
+    if !member_state(exec).down.swap(true, Ordering::SeqCst) {
+        warn!("Upstream {exec} is marked down");
+    }
+}
+
+fn mark_up(exec: &str) {
+    if member_state(exec).down.swap(false, Ordering::SeqCst) {
+        info!("Upstream {exec} is back up");
+    }
+}
+
+/* Members in the order they should be tried. Members that are down go
+ * last, so a pool with every member down still gets a chance
+ */
+pub fn ordered_members(pool: &UpstreamPool) -> Vec<UpstreamPoolMember> {
+    let mut members = pool.members.clone();
+
+    match pool.strategy {
+        PoolStrategy::Failover => {}
+        PoolStrategy::RoundRobin => {
+            if !members.is_empty() {
+                let mut counters = ROUND_ROBIN.lock().unwrap();
+
+                let counter = counters.entry(pool.name.clone()).or_insert(0);
+
+                let start = *counter % members.len();
+
+                members.rotate_left(start);
+
+                *counter = counter.wrapping_add(1);
+            }
+        }
+        PoolStrategy::LeastConn => {
+            members.sort_by_key(|member| member_state(&member.exec).active.load(Ordering::SeqCst));
+        }
+    }
+
+    members.sort_by_key(|member| member_state(&member.exec).down.load(Ordering::SeqCst));
+
+    members
+}
+
+async fn probe(exec: &str) -> bool {
+    let Some(proxy) = exec
+        .split_once(' ')
+        .and_then(|(_, spec)| UpstreamProxy::parse(spec).ok())
+    else {
+        return false;
+    };
+
+    matches!(
+        tokio::time::timeout(HEALTH_CHECK_TIMEOUT, proxy.connect()).await,
+        Ok(Ok(_))
+    )
+}
+
+pub fn start_health_checks() {
+    tokio::spawn(async {
+        info!("Upstream health check task was started");
+
+        loop {
+            let options = parse_args().upstream_options;
+
+            /* An interval of 0 disables the checks */
+            if options.health_check_interval == 0 {
+                tokio::time::sleep(HEALTH_CHECK_DISABLED_POLL).await;
+
+                continue;
+            }
+
+            for pool in &options.pools {
+                for member in &pool.members {
+                    if probe(&member.exec).await {
+                        mark_up(&member.exec);
+                    } else {
+                        mark_down(&member.exec);
+                    }
+                }
+            }
+
+            tokio::time::sleep(Duration::from_millis(options.health_check_interval)).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* Member state is global, so every test uses its own execs and pool names */
+    fn pool(name: &str, strategy: PoolStrategy, members: &[&str]) -> UpstreamPool {
+        UpstreamPool {
+            name: name.to_string(),
+            strategy,
+            members: members
+                .iter()
+                .map(|exec| UpstreamPoolMember {
+                    exec: exec.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    fn execs(members: Vec<UpstreamPoolMember>) -> Vec<String> {
+        members.into_iter().map(|member| member.exec).collect()
+    }
+
+    #[test]
+    fn failover_keeps_the_written_order() {
+        let pool = pool(
+            "failover order",
+            PoolStrategy::Failover,
+            &[
+                "socks5 10.0.1.1:1080",
+                "socks5 10.0.1.2:1080",
+                "http 10.0.1.3:3128",
+            ],
+        );
+
+        for _ in 0..3 {
+            assert_eq!(
+                execs(ordered_members(&pool)),
+                [
+                    "socks5 10.0.1.1:1080",
+                    "socks5 10.0.1.2:1080",
+                    "http 10.0.1.3:3128"
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn failover_moves_down_members_last() {
+        let pool = pool(
+            "failover down",
+            PoolStrategy::Failover,
+            &[
+                "socks5 10.0.2.1:1080",
+                "socks5 10.0.2.2:1080",
+                "socks5 10.0.2.3:1080",
+            ],
+        );
+
+        mark_down("socks5 10.0.2.1:1080");
+
+        assert_eq!(
+            execs(ordered_members(&pool)),
+            [
+                "socks5 10.0.2.2:1080",
+                "socks5 10.0.2.3:1080",
+                "socks5 10.0.2.1:1080"
+            ]
+        );
+
+        mark_up("socks5 10.0.2.1:1080");
+
+        assert_eq!(
+            execs(ordered_members(&pool)),
+            [
+                "socks5 10.0.2.1:1080",
+                "socks5 10.0.2.2:1080",
+                "socks5 10.0.2.3:1080"
+            ]
+        );
+    }
+
+    #[test]
+    fn every_member_down_still_gets_tried() {
+        let pool = pool(
+            "all down",
+            PoolStrategy::Failover,
+            &["socks5 10.0.3.1:1080", "socks5 10.0.3.2:1080"],
+        );
+
+        mark_down("socks5 10.0.3.1:1080");
+        mark_down("socks5 10.0.3.2:1080");
+
+        assert_eq!(
+            execs(ordered_members(&pool)),
+            ["socks5 10.0.3.1:1080", "socks5 10.0.3.2:1080"]
+        );
+    }
+
+    #[test]
+    fn round_robin_rotates_the_first_member() {
+        let pool = pool(
+            "round robin",
+            PoolStrategy::RoundRobin,
+            &[
+                "socks5 10.0.4.1:1080",
+                "socks5 10.0.4.2:1080",
+                "socks5 10.0.4.3:1080",
+            ],
+        );
+
+        let firsts: Vec<String> = (0..4)
+            .map(|_| execs(ordered_members(&pool)).remove(0))
+            .collect();
+
+        assert_eq!(
+            firsts,
+            [
+                "socks5 10.0.4.1:1080",
+                "socks5 10.0.4.2:1080",
+                "socks5 10.0.4.3:1080",
+                "socks5 10.0.4.1:1080"
+            ]
+        );
+
+        assert_eq!(
+            execs(ordered_members(&pool)),
+            [
+                "socks5 10.0.4.2:1080",
+                "socks5 10.0.4.3:1080",
+                "socks5 10.0.4.1:1080"
+            ]
+        );
+    }
+
+    #[test]
+    fn least_conn_follows_the_leases() {
+        let pool = pool(
+            "least conn",
+            PoolStrategy::LeastConn,
+            &["socks5 10.0.5.1:1080", "socks5 10.0.5.2:1080"],
+        );
+
+        let first = lease("socks5 10.0.5.1:1080");
+
+        assert_eq!(
+            execs(ordered_members(&pool)),
+            ["socks5 10.0.5.2:1080", "socks5 10.0.5.1:1080"]
+        );
+
+        let second = lease("socks5 10.0.5.2:1080");
+        let third = lease("socks5 10.0.5.2:1080");
+
+        assert_eq!(
+            execs(ordered_members(&pool)),
+            ["socks5 10.0.5.1:1080", "socks5 10.0.5.2:1080"]
+        );
+
+        drop((first, second, third));
+
+        assert_eq!(
+            member_state("socks5 10.0.5.1:1080")
+                .active
+                .load(Ordering::SeqCst),
+            0
+        );
+        assert_eq!(
+            member_state("socks5 10.0.5.2:1080")
+                .active
+                .load(Ordering::SeqCst),
+            0
+        );
+    }
+}
@@ -0,0 +1,67 @@
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+
+use anyhow::{anyhow, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::{UpstreamProxy, UpstreamTarget};
+
+const CMD_CONNECT: u8 = 0x01;
+
+const REPLY_GRANTED: u8 = 0x5A;
+
+/* Domains go out in the SOCKS4a form, 0.0.0.x followed by the name */
+fn connect_request(proxy: &UpstreamProxy, target: &UpstreamTarget) -> Result<Vec<u8>> {
+    let userid = proxy
+        .credentials
+        .as_ref()
+        .map(|(username, _)| username.as_str())
+        .unwrap_or("");
+
+    let mut request = vec![4, CMD_CONNECT];
+
+    match target {
+        UpstreamTarget::Addr(SocketAddr::V4(addr)) => {
+            request.extend_from_slice(&addr.port().to_be_bytes());
+            request.extend_from_slice(&addr.ip().octets());
+            request.extend_from_slice(userid.as_bytes());
+            request.push(0);
+        }
+        UpstreamTarget::Addr(SocketAddr::V6(addr)) => {
+            return Err(anyhow!("SOCKS4 upstreams can't reach IPv6 address {addr}"));
+        }
+        UpstreamTarget::Domain(domain, port) => {
+            request.extend_from_slice(&port.to_be_bytes());
+            request.extend_from_slice(&[0, 0, 0, 1]);
+            request.extend_from_slice(userid.as_bytes());
+            request.push(0);
+            request.extend_from_slice(domain.as_bytes());
+            request.push(0);
+        }
+    }
+
+    Ok(request)
+}
+
+pub async fn connect(proxy: &UpstreamProxy, target: &UpstreamTarget) -> Result<TcpStream> {
+    let request = connect_request(proxy, target)?;
+
+    let mut socket = proxy.connect().await?;
+
+    socket.write_all(&request).await?;
+
+    let mut reply = [0u8; 8];
+
+    socket.read_exact(&mut reply).await?;
+
+    if reply[1] != REPLY_GRANTED {
+        return Err(std::io::Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("Upstream SOCKS4 proxy has replied {:#04x}", reply[1]),
+        )
+        .into());
+    }
+
+    Ok(socket)
+}
@@ -0,0 +1,152 @@
+use std::io::ErrorKind;
+
+use anyhow::{anyhow, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::{UpstreamProxy, UpstreamTarget};
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERPASS: u8 = 0x02;
+
+const USERPASS_VERSION: u8 = 0x01;
+
+fn reply_error(code: u8) -> anyhow::Error {
+    let kind = match code {
+        0x02 => ErrorKind::PermissionDenied,
+        0x03 => ErrorKind::NetworkUnreachable,
+        0x04 => ErrorKind::HostUnreachable,
+        0x05 => ErrorKind::ConnectionRefused,
+        0x06 => ErrorKind::TimedOut,
+        _ => ErrorKind::Other,
+    };
+
+    std::io::Error::new(
+        kind,
+        format!("Upstream SOCKS5 proxy has replied {code:#04x}"),
+    )
+    .into()
+}
+
+async fn authenticate(socket: &mut TcpStream, proxy: &UpstreamProxy) -> Result<()> {
+    let greeting: &[u8] = match proxy.credentials {
+        Some(_) => &[5, 2, METHOD_NO_AUTH, METHOD_USERPASS],
+        None => &[5, 1, METHOD_NO_AUTH],
+    };
+
+    socket.write_all(greeting).await?;
+
+    let mut choice = [0u8; 2];
+
+    socket.read_exact(&mut choice).await?;
+
+    if choice[0] != 5 {
+        return Err(anyhow!("Upstream speaks SOCKS version {}", choice[0]));
+    }
+
+    match (choice[1], &proxy.credentials) {
+        (METHOD_NO_AUTH, _) => Ok(()),
+        (METHOD_USERPASS, Some((username, password))) => {
+            if username.len() > 255 || password.len() > 255 {
+                return Err(anyhow!("Upstream credentials are too long"));
+            }
+
+            let mut request = vec![USERPASS_VERSION, username.len() as u8];
+
+            request.extend_from_slice(username.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+
+            socket.write_all(&request).await?;
+
+            let mut status = [0u8; 2];
+
+            socket.read_exact(&mut status).await?;
+
+            if status[1] != 0 {
+                return Err(std::io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("Upstream has rejected credentials of {username:?}"),
+                )
+                .into());
+            }
+
+            Ok(())
+        }
+        (method, _) => Err(anyhow!(
+            "Upstream has chosen an unusable method {method:#04x}"
+        )),
+    }
+}
+
+fn connect_request(target: &UpstreamTarget) -> Vec<u8> {
+    let mut request = Vec::with_capacity(262);
+
+    request.extend_from_slice(&[5, 1, 0]);
+
+    let port = match target {
+        UpstreamTarget::Addr(std::net::SocketAddr::V4(addr)) => {
+            request.push(1);
+            request.extend_from_slice(&addr.ip().octets());
+
+            addr.port()
+        }
+        UpstreamTarget::Addr(std::net::SocketAddr::V6(addr)) => {
+            request.push(4);
+            request.extend_from_slice(&addr.ip().octets());
+
+            addr.port()
+        }
+        UpstreamTarget::Domain(domain, port) => {
+            request.push(3);
+            request.push(domain.len() as u8);
+            request.extend_from_slice(domain.as_bytes());
+
+            *port
+        }
+    };
+
+    request.extend_from_slice(&port.to_be_bytes());
+
+    request
+}
+
+/* Reads the whole reply, so no part of BND.ADDR leaks into the relayed stream */
+async fn read_reply(socket: &mut TcpStream) -> Result<()> {
+    let mut header = [0u8; 4];
+
+    socket.read_exact(&mut header).await?;
+
+    if header[0] != 5 {
+        return Err(anyhow!("Upstream speaks SOCKS version {}", header[0]));
+    }
+
+    if header[1] != 0 {
+        return Err(reply_error(header[1]));
+    }
+
+    let address_len = match header[3] {
+        1 => 4,
+        3 => socket.read_u8().await? as usize,
+        4 => 16,
+        atyp => return Err(anyhow!("Upstream has replied with address type {atyp}")),
+    };
+
+    let mut bound = vec![0u8; address_len + 2];
+
+    socket.read_exact(&mut bound).await?;
+
+    Ok(())
+}
+
+pub async fn connect(proxy: &UpstreamProxy, target: &UpstreamTarget) -> Result<TcpStream> {
+    let mut socket = proxy.connect().await?;
+
+    authenticate(&mut socket, proxy).await?;
+
+    socket.write_all(&connect_request(target)).await?;
+
+    read_reply(&mut socket).await?;
+
+    Ok(socket)
+}
@@ -1,8 +1,11 @@
+use std::net::{IpAddr, SocketAddr};
 use std::sync::OnceLock;
+use std::time::Duration;
 
 pub mod parser;
 
 use wfblmark::is_16kb_blocked;
+use wfcipu::parsers::ip::supports_ipv6;
 use wfconfig::parse_args;
 
 use crate::parser::{create_queries, parse_dns_response};
@@ -30,6 +33,8 @@ use curl::easy::Easy;
 
 static DNS_SERVERS: OnceLock<Vec<String>> = OnceLock::new();
 
+const RESOLUTION_DELAY: Duration = Duration::from_millis(50);
+
 pub async fn test_dns_servers() {
     let config = parse_args();
 
@@ -108,7 +113,7 @@ impl DOHResolver {
 
             drop(transfer);
 
-            return Ok(response_data);
+            Ok(response_data)
         })
         .await?;
 
@@ -136,19 +141,45 @@ impl DOHResolver {
         panic!("DoH multiplexer is not available on targets without direct OpenSSL support");
     }
 
-    pub async fn doh_resolver(domain: String) -> Result<String> {
+    /* Orders the addresses so that the ones not marked as 16kb-blocked come first */
+    async fn prioritize(addrs: Vec<IpAddr>) -> Vec<IpAddr> {
+        let mut usable = vec![];
+        let mut blocked = vec![];
+
+        for addr in addrs {
+            if usable.contains(&addr) || blocked.contains(&addr) {
+                continue;
+            }
+
+            if is_16kb_blocked(SocketAddr::new(addr, 443)).await {
+                blocked.push(addr);
+            } else {
+                usable.push(addr);
+            }
+        }
+
+        usable.extend(blocked);
+
+        usable
+    }
+
+    pub async fn doh_resolver(domain: String) -> Result<Vec<IpAddr>> {
         let config = parse_args();
 
         if !config.dns_options.integrated_doh_enabled {
-            let mut addrs = tokio::net::lookup_host(format!("{}:443", domain)).await?;
-
-            match addrs.next() {
-                Some(n) => {
-                    warn!("The ISP will see that you've accessed {domain} -> {n}");
-                    return Ok(n.to_string().replace(":443", ""));
-                }
-                None => error!("No addresses for {domain} from system DNS"),
-            };
+            let addrs: Vec<IpAddr> = tokio::net::lookup_host(format!("{}:443", domain))
+                .await?
+                .map(|addr| addr.ip())
+                .filter(|ip| ip.is_ipv4() || supports_ipv6())
+                .collect();
+
+            if addrs.is_empty() {
+                error!("No addresses for {domain} from system DNS");
+            } else {
+                warn!("The ISP will see that you've accessed {domain} -> {addrs:?}");
+
+                return Ok(Self::prioritize(addrs).await);
+            }
         }
 
         let queries = create_queries(&domain)?;
@@ -158,50 +189,62 @@ impl DOHResolver {
             .get()
             .ok_or(anyhow!("DNS_SERVERS aren't initialized yet"))?
         {
-            for query in queries.clone() {
+            for (family, query) in queries.clone().into_iter().enumerate() {
                 let task = Self::resolve_with(dns, query);
 
-                tasks.push(task);
+                tasks.push((family, task));
             }
         }
 
         let mut tasks: Vec<_> = tasks
             .into_iter()
-            .map(|task| {
+            .map(|(family, task)| {
                 Box::pin(async move {
                     match task.await {
-                        Ok(bytes) => match parse_dns_response(&bytes) {
-                            Ok(addr) => Some(addr.ip().to_string()),
-                            Err(_) => None,
-                        },
-                        Err(_) => None,
+                        Ok(bytes) => (family, parse_dns_response(&bytes).ok()),
+                        Err(_) => (family, None),
                     }
                 })
             })
             .collect();
 
+        /* Queries go out as A then AAAA, there's nothing to wait for
+         * from the AAAA ones if IPv6 can't be used anyway
+         */
+        let mut answered = [false, !supports_ipv6()];
         let mut ips = vec![];
 
-        while !tasks.is_empty() {
-            let (result, _index, remaining) = futures::future::select_all(tasks).await;
-
-            if let Some(ip) = result {
-                let addr = ip;
+        while !tasks.is_empty() && !answered.iter().all(|done| *done) {
+            let next = futures::future::select_all(tasks);
 
-                if !is_16kb_blocked((addr.clone() + ":443").parse()?).await {
-                    return Ok(addr);
+            /* Once one family has answered, the other one only gets a short
+             * grace period (RFC 8305 Resolution Delay) before we move on
+             */
+            let ((family, result), _index, remaining) = if answered.iter().any(|done| *done) {
+                match tokio::time::timeout(RESOLUTION_DELAY, next).await {
+                    Ok(completed) => completed,
+                    Err(_) => break,
                 }
+            } else {
+                next.await
+            };
+
+            if let Some(addrs) = result {
+                answered[family] = true;
 
-                ips.push(addr);
+                ips.extend(addrs);
             }
 
             tasks = remaining;
         }
 
-        if ips.len() != 0 {
-            Ok(ips[0].clone())
-        } else {
-            Err(anyhow!("Did not resolve {domain}"))
+        if ips.is_empty() {
+            return Err(anyhow!("Did not resolve {domain}"));
         }
+
+        /* IPv6 goes first, connect_socket interleaves the families itself */
+        ips.sort_by_key(|ip| ip.is_ipv4());
+
+        Ok(Self::prioritize(ips).await)
     }
 }
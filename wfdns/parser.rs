@@ -1,4 +1,4 @@
-use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::net::IpAddr;
 
 use anyhow::{anyhow, Result};
 use base64::{
@@ -30,8 +30,7 @@ pub fn create_queries(domain: &str) -> Result<Vec<String>> {
     Ok(queries)
 }
 
-#[allow(mismatched_lifetime_syntaxes)]
-pub async fn parse<'b>(buffer: &'b [u8]) -> Result<IpParser> {
+pub async fn parse(buffer: &[u8]) -> Result<IpParser> {
     let dest_addr_type = buffer[3];
     let is_udp = buffer[1] == 0x03;
 
@@ -46,6 +45,7 @@ pub async fn parse<'b>(buffer: &'b [u8]) -> Result<IpParser> {
                     host_unprocessed: vec![0, 0, 0, 0],
                     port: 0,
                     is_udp,
+                    resolved: vec![],
                 });
             }
 
@@ -55,6 +55,7 @@ pub async fn parse<'b>(buffer: &'b [u8]) -> Result<IpParser> {
                 host_unprocessed: buffer[4..8].to_vec(),
                 port: u16::from_be_bytes([buffer[8], buffer[9]]),
                 is_udp,
+                resolved: vec![],
             })
         }
         3 => {
@@ -69,6 +70,7 @@ pub async fn parse<'b>(buffer: &'b [u8]) -> Result<IpParser> {
                     host_unprocessed: vec![0, 0, 0, 0],
                     port: 0,
                     is_udp,
+                    resolved: vec![],
                 });
             }
 
@@ -88,25 +90,29 @@ pub async fn parse<'b>(buffer: &'b [u8]) -> Result<IpParser> {
                         host_unprocessed: domain.to_vec(),
                         port,
                         is_udp,
+                        resolved: vec![],
                     });
                 }
 
                 match DOHResolver::doh_resolver(domain_str.to_string()).await {
-                    Ok(ip) => {
-                        if let Ok(ip_addr) = ip.parse::<IpAddr>() {
-                            let ip_buffer = match ip_addr {
-                                IpAddr::V4(ip) => ip.octets().to_vec(),
-                                IpAddr::V6(ip) => ip.octets().to_vec(),
-                            };
-
-                            return Ok(IpParser {
-                                dest_addr_type,
-                                host_raw: ip_buffer,
-                                host_unprocessed: domain.to_vec(),
-                                port,
-                                is_udp,
-                            });
-                        }
+                    Ok(resolved) if !resolved.is_empty() => {
+                        let ip_buffer = match resolved[0] {
+                            IpAddr::V4(ip) => ip.octets().to_vec(),
+                            IpAddr::V6(ip) => ip.octets().to_vec(),
+                        };
+
+                        return Ok(IpParser {
+                            dest_addr_type,
+                            host_raw: ip_buffer,
+                            host_unprocessed: domain.to_vec(),
+                            port,
+                            is_udp,
+                            resolved,
+                        });
+                    }
+
+                    Ok(_) => {
+                        error!("DoH resolver returned no addresses for {domain_str}");
                     }
 
                     Err(error) => {
@@ -121,6 +127,7 @@ pub async fn parse<'b>(buffer: &'b [u8]) -> Result<IpParser> {
                 host_unprocessed: domain.to_vec(),
                 port,
                 is_udp,
+                resolved: vec![],
             })
         }
         4 => {
@@ -131,6 +138,7 @@ pub async fn parse<'b>(buffer: &'b [u8]) -> Result<IpParser> {
                     host_unprocessed: vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
                     port: 0,
                     is_udp,
+                    resolved: vec![],
                 });
             }
 
@@ -140,6 +148,7 @@ pub async fn parse<'b>(buffer: &'b [u8]) -> Result<IpParser> {
                 host_unprocessed: buffer[4..20].to_vec(),
                 port: u16::from_be_bytes([buffer[20], buffer[21]]),
                 is_udp,
+                resolved: vec![],
             })
         }
         _ => Ok(IpParser {
@@ -148,29 +157,26 @@ pub async fn parse<'b>(buffer: &'b [u8]) -> Result<IpParser> {
             host_unprocessed: vec![0, 0, 0, 0],
             port: 13437,
             is_udp,
+            resolved: vec![],
         }),
     }
 }
 
 /* https://lib.rs/crates/rusdig "Parsing a Response:" */
-pub fn parse_dns_response(response_bytes: &[u8]) -> Result<SocketAddr> {
+pub fn parse_dns_response(response_bytes: &[u8]) -> Result<Vec<IpAddr>> {
     let response = Query::from_bytes(response_bytes)?;
 
-    let mut ips: Vec<SocketAddr> = vec![];
+    let mut ips: Vec<IpAddr> = vec![];
 
     if response.flags.successful() {
         for answer in &response.resource_answers {
             match answer.entry_type().ok_or(anyhow!("No entry"))? {
                 rusdig::RecordType::A => {
-                    let ipv4 = answer.data_as_ipv4()?;
-
-                    ips.push(SocketAddr::V4(SocketAddrV4::new(ipv4, 443)));
+                    ips.push(IpAddr::V4(answer.data_as_ipv4()?));
                 }
 
                 rusdig::RecordType::AAAA => {
-                    let ipv6 = answer.data_as_ipv6()?;
-
-                    ips.push(SocketAddr::V6(SocketAddrV6::new(ipv6, 443, 0, 0)));
+                    ips.push(IpAddr::V6(answer.data_as_ipv6()?));
                 }
 
                 _ => {}
@@ -180,18 +186,11 @@ pub fn parse_dns_response(response_bytes: &[u8]) -> Result<SocketAddr> {
         return Err(anyhow!("Bad DNS record"));
     }
 
-    /* Try to find and return an IPv6, if the specified interface supports it */
+    /* IPv6 answers are useless if the specified interface doesn't support it */
 
-    if supports_ipv6() {
-        let ipv6_ip = ips.iter().find(|e| e.is_ipv6());
-
-        match ipv6_ip {
-            Some(ip) => return Ok(*ip),
-            None => {}
-        }
+    if !supports_ipv6() {
+        ips.retain(|ip| ip.is_ipv4());
     }
 
-    let ipv4_ip = ips.iter().find(|e| e.is_ipv4());
-
-    Ok(*(ipv4_ip.ok_or(anyhow!("No IPv4 available on the DNS"))?))
+    Ok(ips)
 }
@@ -0,0 +1,112 @@
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use wfconfig::aux_config::AuxConfig;
+use wfcore::router::ClientIdentity;
+
+use crate::listener::ClientStream;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERPASS: u8 = 0x02;
+const METHOD_UNACCEPTABLE: u8 = 0xFF;
+
+const USERPASS_VERSION: u8 = 0x01;
+
+pub async fn negotiate_auth<S: ClientStream>(
+    client: &mut S,
+    config: &AuxConfig,
+    identity: ClientIdentity,
+) -> Result<ClientIdentity> {
+    let mut header = [0u8; 2];
+
+    client.read_exact(&mut header).await?;
+
+    if header[0] != 5 {
+        return Err(anyhow!("Unsupported SOCKS version {}", header[0]));
+    }
+
+    let mut methods = vec![0u8; header[1] as usize];
+
+    client.read_exact(&mut methods).await?;
+
+    let auth_required = !config.auth_options.users.is_empty();
+
+    let method = if auth_required {
+        METHOD_USERPASS
+    } else {
+        METHOD_NO_AUTH
+    };
+
+    if !methods.contains(&method) {
+        client.write_all(&[5, METHOD_UNACCEPTABLE]).await?;
+
+        return Err(anyhow!(
+            "No acceptable authentication methods in {methods:?}"
+        ));
+    }
+
+    client.write_all(&[5, method]).await?;
+
+    if !auth_required {
+        return Ok(identity);
+    }
+
+    let (username, password) = read_credentials(client).await?;
+
+    if !check_credentials(config, &username, &password) {
+        client.write_all(&[USERPASS_VERSION, 1]).await?;
+
+        warn!("Rejected SOCKS5 credentials for user {username:?}");
+
+        return Err(anyhow!("Authentication failed for user {username:?}"));
+    }
+
+    client.write_all(&[USERPASS_VERSION, 0]).await?;
+
+    info!("User {username:?} has been authenticated");
+
+    Ok(ClientIdentity {
+        user: Some(username),
+        ..identity
+    })
+}
+
+pub fn check_credentials(config: &AuxConfig, username: &str, password: &str) -> bool {
+    config
+        .auth_options
+        .users
+        .iter()
+        .any(|user| user.username == username && user.password == password)
+}
+
+async fn read_credentials<S: ClientStream>(client: &mut S) -> Result<(String, String)> {
+    let mut header = [0u8; 2];
+
+    client.read_exact(&mut header).await?;
+
+    if header[0] != USERPASS_VERSION {
+        client.write_all(&[USERPASS_VERSION, 1]).await?;
+
+        return Err(anyhow!(
+            "Unsupported username/password subnegotiation version {}",
+            header[0]
+        ));
+    }
+
+    let mut username = vec![0u8; header[1] as usize];
+
+    client.read_exact(&mut username).await?;
+
+    let mut password_len = [0u8; 1];
+
+    client.read_exact(&mut password_len).await?;
+
+    let mut password = vec![0u8; password_len[0] as usize];
+
+    client.read_exact(&mut password).await?;
+
+    Ok((
+        String::from_utf8_lossy(&username).to_string(),
+        String::from_utf8_lossy(&password).to_string(),
+    ))
+}
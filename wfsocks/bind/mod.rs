@@ -0,0 +1,131 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{error, info, warn};
+use wfcipu::parsers::ip::IpParser;
+use wfconfig::{parse_args, NetworkProtocol};
+use wfcore::router::{ClientIdentity, RouteAction, RouteContext, Router};
+use wfcore::socket::SocketOps;
+
+use crate::handshake::{send_reply, send_reply_addr, ReplyCode};
+use crate::listener::ClientStream;
+
+const BIND_ACCEPT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/* DST.ADDR of a BIND request is only a hint about the expected peer,
+ * so an all-zeros address is accepted here unlike in CONNECT
+ */
+fn expected_peer(parsed_data: &IpParser) -> Result<SocketAddr> {
+    let ip: IpAddr = match parsed_data.host_raw.len() {
+        4 => {
+            let octets: [u8; 4] = parsed_data.host_raw[..4].try_into()?;
+
+            Ipv4Addr::from(octets).into()
+        }
+        16 => {
+            let octets: [u8; 16] = parsed_data.host_raw[..16].try_into()?;
+
+            Ipv6Addr::from(octets).into()
+        }
+        _ => return Err(anyhow!("No IP")),
+    };
+
+    Ok(SocketAddr::new(ip, parsed_data.port))
+}
+
+/* The peer dials in on our own listener, so only direct routes make sense */
+async fn route_bind(
+    parsed_data: &IpParser,
+    peer: SocketAddr,
+    identity: &ClientIdentity,
+) -> Result<()> {
+    let route = RouteContext {
+        domain: (parsed_data.dest_addr_type == 3)
+            .then(|| String::from_utf8_lossy(&parsed_data.host_unprocessed).to_string()),
+        ip: Some(peer.ip()).filter(|ip| !ip.is_unspecified()),
+        port: Some(peer.port()),
+        protocol: Some(NetworkProtocol::TCP),
+        identity: identity.clone(),
+        ..Default::default()
+    };
+
+    match Router::route(&parse_args(), &route).await?.action {
+        RouteAction::Direct => Ok(()),
+        RouteAction::Block => Err(anyhow!("BIND for {peer} has been blocked by a router rule")),
+        action => Err(anyhow!("BIND for {peer} can't be forwarded with {action:?}")),
+    }
+}
+
+/* RFC 1928 BIND: the first reply carries the address we listen on,
+ * the second one is sent once the peer connects to it
+ */
+pub async fn socks5_bind<S: ClientStream + 'static>(
+    mut client: S,
+    parsed_data: &IpParser,
+    identity: &ClientIdentity,
+) -> Result<()> {
+    let peer = match expected_peer(parsed_data) {
+        Ok(peer) => peer,
+        Err(error) => {
+            send_reply(&mut client, ReplyCode::HostUnreachable).await?;
+
+            return Err(error);
+        }
+    };
+
+    if let Err(error) = route_bind(parsed_data, peer, identity).await {
+        send_reply(&mut client, ReplyCode::NotAllowed).await?;
+
+        return Err(error);
+    }
+
+    let (listener, bound_addr) = match SocketOps::bind_listener(peer).await {
+        Ok(bound) => bound,
+        Err(error) => {
+            let code = ReplyCode::from_error(&error);
+
+            error!("Could not bind a listener for {peer}: {error}, replying {code:?}");
+
+            send_reply(&mut client, code).await?;
+
+            return Err(error);
+        }
+    };
+
+    info!("Listening on {bound_addr} for a BIND peer {peer}");
+
+    send_reply_addr(&mut client, ReplyCode::Succeeded, bound_addr).await?;
+
+    let (mut socket, peer_addr) =
+        match tokio::time::timeout(BIND_ACCEPT_TIMEOUT, listener.accept()).await {
+            Ok(Ok(accepted)) => accepted,
+            Ok(Err(error)) => {
+                send_reply(&mut client, ReplyCode::GeneralFailure).await?;
+
+                return Err(error.into());
+            }
+            Err(_) => {
+                send_reply(&mut client, ReplyCode::TtlExpired).await?;
+
+                return Err(anyhow!("No incoming connection for {bound_addr} in time"));
+            }
+        };
+
+    drop(listener);
+
+    if !peer.ip().is_unspecified() && peer.ip() != peer_addr.ip() {
+        warn!("BIND peer {peer_addr} differs from the expected {peer}");
+    }
+
+    let _ = socket.set_nodelay(true);
+
+    send_reply_addr(&mut client, ReplyCode::Succeeded, peer_addr).await?;
+
+    /* The inbound peer isn't a server being reached through a censor,
+     * desync and the 16kb heuristic have nothing to do here
+     */
+    let _ = tokio::io::copy_bidirectional(&mut client, &mut socket).await;
+
+    Ok(())
+}
@@ -0,0 +1,283 @@
+use std::future::Future;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+
+use anyhow::{anyhow, Error, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use wfconfig::aux_config::SocketOptions;
+use wfconfig::parse_args;
+use wfcore::socket::BlockedByRouter;
+
+use crate::listener::ClientStream;
+
+pub const CMD_CONNECT: u8 = 0x01;
+pub const CMD_BIND: u8 = 0x02;
+pub const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplyCode {
+    Succeeded = 0x00,
+    GeneralFailure = 0x01,
+    NotAllowed = 0x02,
+    NetworkUnreachable = 0x03,
+    HostUnreachable = 0x04,
+    ConnectionRefused = 0x05,
+    TtlExpired = 0x06,
+    CommandNotSupported = 0x07,
+    AddressTypeNotSupported = 0x08,
+}
+
+impl ReplyCode {
+    pub fn from_error(error: &Error) -> ReplyCode {
+        if error.downcast_ref::<BlockedByRouter>().is_some() {
+            return ReplyCode::NotAllowed;
+        }
+
+        match error.downcast_ref::<std::io::Error>().map(|e| e.kind()) {
+            Some(ErrorKind::PermissionDenied) => ReplyCode::NotAllowed,
+            Some(ErrorKind::ConnectionRefused) => ReplyCode::ConnectionRefused,
+            Some(ErrorKind::HostUnreachable) => ReplyCode::HostUnreachable,
+            Some(ErrorKind::NetworkUnreachable) => ReplyCode::NetworkUnreachable,
+            Some(ErrorKind::TimedOut) => ReplyCode::TtlExpired,
+            _ => ReplyCode::GeneralFailure,
+        }
+    }
+}
+
+/* Bounds everything the client has to send before the relay starts,
+ * so a peer that connects and goes silent doesn't pin a task forever
+ */
+pub async fn within_handshake<T>(negotiation: impl Future<Output = Result<T>>) -> Result<T> {
+    let timeout = SocketOptions::timeout(parse_args().socket_options.handshake_timeout);
+
+    match tokio::time::timeout(timeout, negotiation).await {
+        Ok(result) => result,
+        Err(_) => {
+            Err(std::io::Error::new(ErrorKind::TimedOut, "Client handshake has timed out").into())
+        }
+    }
+}
+
+pub struct Socks5Request {
+    pub command: u8,
+    pub raw: Vec<u8>,
+}
+
+/* Reads exactly one SOCKS5 request off the stream and answers the ones
+ * that can't be served. `raw` keeps the original wire format, since the
+ * router and the resolver consume it as is
+ */
+pub async fn read_request<S: ClientStream>(client: &mut S) -> Result<Socks5Request> {
+    let mut header = [0u8; 4];
+
+    client.read_exact(&mut header).await?;
+
+    if header[0] != 5 {
+        return Err(anyhow!("Unsupported SOCKS version {}", header[0]));
+    }
+
+    let mut raw = header.to_vec();
+
+    let address_len = match header[3] {
+        1 => 4,
+        3 => {
+            let mut domain_len = [0u8; 1];
+
+            client.read_exact(&mut domain_len).await?;
+
+            raw.push(domain_len[0]);
+
+            domain_len[0] as usize
+        }
+        4 => 16,
+        atyp => {
+            send_reply(client, ReplyCode::AddressTypeNotSupported).await?;
+
+            return Err(anyhow!("Unknown destination type: {atyp}"));
+        }
+    };
+
+    let mut address = vec![0u8; address_len + 2];
+
+    client.read_exact(&mut address).await?;
+
+    raw.extend_from_slice(&address);
+
+    if ![CMD_CONNECT, CMD_BIND, CMD_UDP_ASSOCIATE].contains(&header[1]) {
+        send_reply(client, ReplyCode::CommandNotSupported).await?;
+
+        return Err(anyhow!("Unsupported SOCKS5 command {}", header[1]));
+    }
+
+    Ok(Socks5Request {
+        command: header[1],
+        raw,
+    })
+}
+
+pub async fn send_reply<S: ClientStream>(client: &mut S, code: ReplyCode) -> Result<()> {
+    client
+        .write_all(&[5, code as u8, 0, 1, 0, 0, 0, 0, 0, 0])
+        .await?;
+
+    Ok(())
+}
+
+pub async fn send_reply_addr<S: ClientStream>(
+    client: &mut S,
+    code: ReplyCode,
+    addr: SocketAddr,
+) -> Result<()> {
+    let mut packet: Vec<u8> = vec![5, code as u8, 0];
+
+    match addr {
+        SocketAddr::V4(addr) => {
+            packet.push(1);
+            packet.extend_from_slice(&addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            packet.push(4);
+            packet.extend_from_slice(&addr.ip().octets());
+        }
+    }
+
+    packet.extend_from_slice(&addr.port().to_be_bytes());
+
+    client.write_all(&packet).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use tokio::io::duplex;
+
+    /* Feeds the request to read_request and collects what it replied */
+    fn exchange(request: &[u8]) -> (Result<Socks5Request>, Vec<u8>) {
+        block_on(async {
+            let (mut client, mut server) = duplex(1024);
+
+            client.write_all(request).await.unwrap();
+            client.shutdown().await.unwrap();
+
+            let result = read_request(&mut server).await;
+
+            drop(server);
+
+            let mut reply = vec![];
+
+            client.read_to_end(&mut reply).await.unwrap();
+
+            (result, reply)
+        })
+    }
+
+    fn reply_for(code: ReplyCode) -> Vec<u8> {
+        vec![5, code as u8, 0, 1, 0, 0, 0, 0, 0, 0]
+    }
+
+    #[test]
+    fn reads_every_address_type() {
+        let cases: [(&str, Vec<u8>); 4] = [
+            ("ipv4", vec![5, 1, 0, 1, 1, 1, 1, 1, 0, 80]),
+            (
+                "ipv6",
+                vec![
+                    5, 1, 0, 4, 0x20, 1, 0xd, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 187,
+                ],
+            ),
+            (
+                "domain",
+                [&[5, 1, 0, 3, 11][..], b"example.com", &[0, 53]].concat(),
+            ),
+            ("udp associate", vec![5, 3, 0, 1, 0, 0, 0, 0, 0, 0]),
+        ];
+
+        for (name, request) in cases {
+            let (result, reply) = exchange(&request);
+
+            let parsed = result.unwrap_or_else(|error| panic!("{name}: {error}"));
+
+            assert_eq!(parsed.command, request[1], "{name}");
+            assert_eq!(parsed.raw, request, "{name}");
+            assert!(reply.is_empty(), "{name}");
+        }
+    }
+
+    #[test]
+    fn leaves_the_payload_after_the_request() {
+        block_on(async {
+            let (mut client, mut server) = duplex(1024);
+
+            client
+                .write_all(&[5, 1, 0, 1, 127, 0, 0, 1, 1, 187, 0x16, 3, 1])
+                .await
+                .unwrap();
+
+            let request = read_request(&mut server).await.unwrap();
+
+            assert_eq!(request.raw.len(), 10);
+
+            let mut rest = [0u8; 3];
+
+            server.read_exact(&mut rest).await.unwrap();
+
+            assert_eq!(rest, [0x16, 3, 1]);
+        });
+    }
+
+    #[test]
+    fn answers_what_it_cannot_serve() {
+        let cases: [(&str, Vec<u8>, Option<ReplyCode>); 3] = [
+            (
+                "unknown address type",
+                vec![5, 1, 0, 2, 1, 1, 1, 1, 0, 80],
+                Some(ReplyCode::AddressTypeNotSupported),
+            ),
+            (
+                "unknown command",
+                vec![5, 9, 0, 1, 1, 1, 1, 1, 0, 80],
+                Some(ReplyCode::CommandNotSupported),
+            ),
+            ("wrong version", vec![4, 1, 0, 1, 1, 1, 1, 1, 0, 80], None),
+        ];
+
+        for (name, request, code) in cases {
+            let (result, reply) = exchange(&request);
+
+            assert!(result.is_err(), "{name}");
+            assert_eq!(reply, code.map(reply_for).unwrap_or_default(), "{name}");
+        }
+    }
+
+    #[test]
+    fn truncated_requests_fail_without_a_reply() {
+        let requests: [&[u8]; 5] = [
+            &[5, 1],
+            &[5, 1, 0, 1, 1, 1, 1],
+            &[5, 1, 0, 3],
+            &[5, 1, 0, 3, 11, b'e', b'x'],
+            &[
+                5, 1, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1,
+            ],
+        ];
+
+        for request in requests {
+            let (result, reply) = exchange(request);
+
+            let Err(error) = result else {
+                panic!("{request:?} was accepted");
+            };
+
+            assert_eq!(
+                error.downcast_ref::<std::io::Error>().map(|e| e.kind()),
+                Some(ErrorKind::UnexpectedEof),
+                "{request:?}"
+            );
+            assert!(reply.is_empty(), "{request:?}");
+        }
+    }
+}
@@ -0,0 +1,619 @@
+use std::net::IpAddr;
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose, Engine as _};
+use log::{error, info, warn};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use wfconfig::parse_args;
+use wfcore::relay::RelayPolicy;
+use wfcore::router::{ClientIdentity, Router};
+
+use crate::auth::check_credentials;
+use crate::handshake::{within_handshake, ReplyCode};
+use crate::listener::ClientStream;
+use crate::pipe::async_pipe::pipe_sockets;
+use crate::target::{
+    connect_target, relay_after_hello, resolve_target, send_initial_data, target_route,
+};
+
+const MAX_HEAD_LEN: usize = 16 * 1024;
+
+/* Hop-by-hop headers which shouldn't reach the origin server */
+const DROPPED_HEADERS: [&str; 4] = [
+    "proxy-connection",
+    "proxy-authorization",
+    "connection",
+    "keep-alive",
+];
+
+struct HttpRequestHead {
+    method: String,
+    target: String,
+    version: String,
+    headers: Vec<(String, String)>,
+}
+
+impl HttpRequestHead {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+async fn read_head<S: ClientStream>(client: &mut S) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut buffer = vec![];
+    let mut chunk = [0u8; 4096];
+
+    loop {
+        let n = client.read(&mut chunk).await?;
+
+        if n == 0 {
+            return Err(anyhow!(
+                "Client closed the connection before sending a request"
+            ));
+        }
+
+        buffer.extend_from_slice(&chunk[..n]);
+
+        if let Some(end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            let leftover = buffer.split_off(end + 4);
+
+            return Ok((buffer, leftover));
+        }
+
+        if buffer.len() > MAX_HEAD_LEN {
+            return Err(anyhow!(
+                "HTTP request head is larger than {MAX_HEAD_LEN} bytes"
+            ));
+        }
+    }
+}
+
+fn parse_head(head: &[u8]) -> Result<HttpRequestHead> {
+    let head = std::str::from_utf8(head)?;
+
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next().ok_or(anyhow!("No request line"))?.split(' ');
+
+    let (Some(method), Some(target), Some(version)) = (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) else {
+        return Err(anyhow!("Malformed request line"));
+    };
+
+    let headers = lines
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    Ok(HttpRequestHead {
+        method: method.to_string(),
+        target: target.to_string(),
+        version: version.to_string(),
+        headers,
+    })
+}
+
+fn split_host_port(authority: &str, default_port: u16) -> Result<(String, u16)> {
+    /* [2001:db8::1]:443 */
+    if let Some(rest) = authority.strip_prefix('[') {
+        let (host, rest) = rest
+            .split_once(']')
+            .ok_or(anyhow!("Unterminated IPv6 literal {authority}"))?;
+
+        let port = match rest.strip_prefix(':') {
+            Some(port) => port.parse()?,
+            None => default_port,
+        };
+
+        return Ok((host.to_string(), port));
+    }
+
+    match authority.rsplit_once(':') {
+        Some((host, port)) => Ok((host.to_string(), port.parse()?)),
+        None => Ok((authority.to_string(), default_port)),
+    }
+}
+
+/* The SOCKS5 request every frontend resolves through, IP literals keep their
+ * own address types so they never hit DNS
+ */
+fn destination_request(host: &str, port: u16) -> Vec<u8> {
+    let mut buffer = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => [&[5, 1, 0, 1][..], &ip.octets()].concat(),
+        Ok(IpAddr::V6(ip)) => [&[5, 1, 0, 4][..], &ip.octets()].concat(),
+        Err(_) => [&[5, 1, 0, 3, host.len() as u8][..], host.as_bytes()].concat(),
+    };
+
+    buffer.extend_from_slice(&port.to_be_bytes());
+
+    buffer
+}
+
+enum BodyFraming {
+    Length(u64),
+    Chunked,
+}
+
+impl BodyFraming {
+    fn of(head: &HttpRequestHead) -> Result<BodyFraming> {
+        if let Some(encoding) = head.header("transfer-encoding") {
+            let last = encoding.rsplit(',').next().unwrap_or_default().trim();
+
+            if !last.eq_ignore_ascii_case("chunked") {
+                return Err(anyhow!("Unsupported transfer encoding {encoding}"));
+            }
+
+            return Ok(BodyFraming::Chunked);
+        }
+
+        match head.header("content-length") {
+            Some(length) => Ok(BodyFraming::Length(length.parse()?)),
+            None => Ok(BodyFraming::Length(0)),
+        }
+    }
+}
+
+async fn forward_line<R, W>(body: &mut R, upstream: &mut W) -> Result<String>
+where
+    R: AsyncBufReadExt + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut line = vec![];
+
+    (&mut *body)
+        .take(MAX_HEAD_LEN as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+
+    if !line.ends_with(b"\n") {
+        return Err(anyhow!("Truncated chunked request body"));
+    }
+
+    upstream.write_all(&line).await?;
+
+    Ok(String::from_utf8_lossy(&line).trim().to_string())
+}
+
+/* Forwards exactly one request body, so whatever the client sends after it
+ * never reaches this origin
+ */
+async fn forward_body<R, W>(body: R, upstream: &mut W, framing: BodyFraming) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut body = BufReader::new(body);
+
+    match framing {
+        BodyFraming::Length(length) => {
+            let copied = tokio::io::copy(&mut (&mut body).take(length), upstream).await?;
+
+            if copied != length {
+                return Err(anyhow!("Client sent {copied} of {length} body bytes"));
+            }
+        }
+        BodyFraming::Chunked => loop {
+            let size_line = forward_line(&mut body, upstream).await?;
+
+            let size = size_line.split(';').next().unwrap_or_default().trim();
+            let size = u64::from_str_radix(size, 16)?;
+
+            if size == 0 {
+                /* Trailers end with an empty line */
+                while !forward_line(&mut body, upstream).await?.is_empty() {}
+
+                break;
+            }
+
+            let copied = tokio::io::copy(&mut (&mut body).take(size), upstream).await?;
+
+            if copied != size {
+                return Err(anyhow!("Truncated chunked request body"));
+            }
+
+            forward_line(&mut body, upstream).await?;
+        },
+    }
+
+    Ok(())
+}
+
+/* The origin closes after one response since it was asked to, the client
+ * gets closed right after it so its next request opens a new connection.
+ * Only the pool lease of the policy matters here, it's held until then
+ */
+async fn relay_single_request<S: ClientStream>(
+    client: S,
+    leftover: Vec<u8>,
+    upstream: TcpStream,
+    policy: RelayPolicy,
+    framing: BodyFraming,
+) -> Result<()> {
+    let _lease = policy.lease;
+
+    let (client_read, mut client_write) = tokio::io::split(client);
+    let (mut upstream_read, mut upstream_write) = upstream.into_split();
+
+    {
+        let download = tokio::io::copy(&mut upstream_read, &mut client_write);
+
+        tokio::pin!(download);
+
+        tokio::select! {
+            copied = &mut download => {
+                copied?;
+            }
+            sent = forward_body(leftover.chain(client_read), &mut upstream_write, framing) => {
+                sent?;
+
+                download.await?;
+            }
+        }
+    }
+
+    client_write.shutdown().await?;
+
+    Ok(())
+}
+
+fn proxy_credentials(head: &HttpRequestHead) -> Option<(String, String)> {
+    let value = head.header("proxy-authorization")?;
+
+    let (scheme, encoded) = value.split_once(' ')?;
+
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let decoded = general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+
+    let (username, password) = decoded.split_once(':')?;
+
+    Some((username.to_string(), password.to_string()))
+}
+
+fn rewrite_head(head: &HttpRequestHead, host: &str, path: &str) -> Vec<u8> {
+    let mut rewritten = format!("{} {} {}\r\n", head.method, path, head.version);
+
+    if head.header("host").is_none() {
+        rewritten.push_str(&format!("Host: {host}\r\n"));
+    }
+
+    for (key, value) in &head.headers {
+        if DROPPED_HEADERS.contains(&key.to_ascii_lowercase().as_str()) {
+            continue;
+        }
+
+        rewritten.push_str(&format!("{key}: {value}\r\n"));
+    }
+
+    /* Every request to another origin ends up on a new upstream socket */
+    rewritten.push_str("Connection: close\r\n\r\n");
+
+    rewritten.into_bytes()
+}
+
+async fn send_status<S: ClientStream>(
+    client: &mut S,
+    status: &str,
+    extra_headers: &str,
+) -> Result<()> {
+    client
+        .write_all(
+            format!("HTTP/1.1 {status}\r\n{extra_headers}Content-Length: 0\r\nConnection: close\r\n\r\n")
+                .as_bytes(),
+        )
+        .await?;
+
+    Ok(())
+}
+
+pub async fn http_proxy<S: ClientStream + 'static>(
+    mut client: S,
+    identity: ClientIdentity,
+) -> Result<()> {
+    let config = parse_args();
+
+    let (raw_head, leftover) = within_handshake(read_head(&mut client)).await?;
+
+    let head = match parse_head(&raw_head) {
+        Ok(head) => head,
+        Err(error) => {
+            send_status(&mut client, "400 Bad Request", "").await?;
+
+            return Err(error);
+        }
+    };
+
+    let identity = if config.auth_options.users.is_empty() {
+        identity
+    } else {
+        match proxy_credentials(&head) {
+            Some((username, password)) if check_credentials(&config, &username, &password) => {
+                info!("User {username:?} has been authenticated");
+
+                ClientIdentity {
+                    user: Some(username),
+                    ..identity
+                }
+            }
+            credentials => {
+                if let Some((username, _)) = credentials {
+                    warn!("Rejected HTTP proxy credentials for user {username:?}");
+                }
+
+                send_status(
+                    &mut client,
+                    "407 Proxy Authentication Required",
+                    "Proxy-Authenticate: Basic realm=\"waterfall\"\r\n",
+                )
+                .await?;
+
+                return Err(anyhow!("HTTP proxy authentication failed"));
+            }
+        }
+    };
+
+    let is_connect = head.method.eq_ignore_ascii_case("CONNECT");
+
+    let target = if is_connect {
+        split_host_port(&head.target, 443).map(|(host, port)| (host, port, None))
+    } else {
+        match head
+            .target
+            .get(..7)
+            .filter(|scheme| scheme.eq_ignore_ascii_case("http://"))
+        {
+            Some(_) => {
+                let rest = &head.target[7..];
+
+                let (authority, path) = match rest.find('/') {
+                    Some(index) => (&rest[..index], &rest[index..]),
+                    None => (rest, "/"),
+                };
+
+                split_host_port(authority, 80)
+                    .map(|(host, port)| (host, port, Some(rewrite_head(&head, authority, path))))
+            }
+            None => Err(anyhow!("Unsupported request target {}", head.target)),
+        }
+    };
+
+    let (host, port, rewritten_head) = match target {
+        Ok(target) if !target.0.is_empty() && target.0.len() <= 255 => target,
+        Ok(target) => {
+            send_status(&mut client, "400 Bad Request", "").await?;
+
+            return Err(anyhow!("Bad destination host {:?}", target.0));
+        }
+        Err(error) => {
+            send_status(&mut client, "400 Bad Request", "").await?;
+
+            return Err(error);
+        }
+    };
+
+    let framing = match rewritten_head
+        .as_ref()
+        .map(|_| BodyFraming::of(&head))
+        .transpose()
+    {
+        Ok(framing) => framing,
+        Err(error) => {
+            send_status(&mut client, "501 Not Implemented", "").await?;
+
+            return Err(error);
+        }
+    };
+
+    let buffer = destination_request(&host, port);
+
+    let parsed_data = resolve_target(config, &buffer, &identity).await?;
+
+    let route = target_route(&parsed_data, &identity);
+
+    /* An SNI rule may decide a tunnel, so its route waits for the ClientHello */
+    if rewritten_head.is_none() && Router::waits_for_sni(&parse_args(), &route).await? {
+        client
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await?;
+
+        return relay_after_hello(client, leftover, &parsed_data, route).await;
+    }
+
+    let (mut socket, policy) = match connect_target(&parsed_data, route).await {
+        Ok(connected) => connected,
+        Err(error) => {
+            let status = match ReplyCode::from_error(&error) {
+                ReplyCode::NotAllowed => "403 Forbidden",
+                ReplyCode::TtlExpired => "504 Gateway Timeout",
+                _ => "502 Bad Gateway",
+            };
+
+            error!("Connection aborted: {error} with a host {host}:{port}, replying {status}");
+
+            send_status(&mut client, status, "").await?;
+
+            return Ok(());
+        }
+    };
+
+    match rewritten_head.zip(framing) {
+        Some((rewritten_head, framing)) => {
+            send_initial_data(&mut socket, &rewritten_head, &policy).await?;
+
+            relay_single_request(client, leftover, socket, policy, framing).await
+        }
+        None => {
+            client
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await?;
+
+            send_initial_data(&mut socket, &leftover, &policy).await?;
+
+            let _ = pipe_sockets(client, socket, policy).await;
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn head(raw: &str) -> HttpRequestHead {
+        parse_head(raw.as_bytes()).unwrap()
+    }
+
+    /* Forwards the body and returns what reached the upstream */
+    fn forward(input: &[u8], framing: BodyFraming) -> Result<Vec<u8>> {
+        block_on(async {
+            let mut upstream = vec![];
+
+            forward_body(input, &mut upstream, framing).await?;
+
+            Ok(upstream)
+        })
+    }
+
+    #[test]
+    fn parses_the_request_head() {
+        let head = head(
+            "GET http://example.com/index.html HTTP/1.1\r\n\
+             Host: example.com\r\n\
+             Proxy-Connection:keep-alive\r\n\
+             X-Empty:\r\n\
+             no colon here\r\n\r\n",
+        );
+
+        assert_eq!(head.method, "GET");
+        assert_eq!(head.target, "http://example.com/index.html");
+        assert_eq!(head.version, "HTTP/1.1");
+        assert_eq!(head.headers.len(), 3);
+
+        assert_eq!(head.header("host"), Some("example.com"));
+        assert_eq!(head.header("PROXY-CONNECTION"), Some("keep-alive"));
+        assert_eq!(head.header("x-empty"), Some(""));
+        assert_eq!(head.header("content-length"), None);
+    }
+
+    #[test]
+    fn rejects_malformed_request_lines() {
+        assert!(parse_head(b"GET /\r\n\r\n").is_err());
+        assert!(parse_head(b"\r\n\r\n").is_err());
+        assert!(parse_head(b"GET / HTTP/1.1\r\nHost: \xff\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn splits_host_and_port() {
+        let cases = [
+            ("example.com:8443", ("example.com", 8443)),
+            ("example.com", ("example.com", 80)),
+            ("192.0.2.1:443", ("192.0.2.1", 443)),
+            ("[2001:db8::1]:443", ("2001:db8::1", 443)),
+            ("[2001:db8::1]", ("2001:db8::1", 80)),
+            ("[::1]:8080", ("::1", 8080)),
+        ];
+
+        for (authority, (host, port)) in cases {
+            assert_eq!(
+                split_host_port(authority, 80).unwrap(),
+                (host.to_string(), port),
+                "{authority}"
+            );
+        }
+
+        assert!(split_host_port("[2001:db8::1:443", 80).is_err());
+        assert!(split_host_port("example.com:https", 80).is_err());
+        assert!(split_host_port("[::1]:99999", 80).is_err());
+    }
+
+    #[test]
+    fn ip_literals_keep_their_address_types() {
+        assert_eq!(
+            destination_request("192.0.2.1", 443),
+            [5, 1, 0, 1, 192, 0, 2, 1, 1, 187]
+        );
+        assert_eq!(
+            destination_request("::1", 80),
+            [&[5, 1, 0, 4][..], &[0; 15], &[1, 0, 80]].concat()
+        );
+        assert_eq!(
+            destination_request("example.com", 80),
+            [&[5, 1, 0, 3, 11][..], b"example.com", &[0, 80]].concat()
+        );
+    }
+
+    #[test]
+    fn picks_the_body_framing() {
+        let chunked = head("POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n");
+        let sized = head("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n");
+        let empty = head("GET / HTTP/1.1\r\n\r\n");
+        let unsupported = head("POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n");
+
+        assert!(matches!(
+            BodyFraming::of(&chunked),
+            Ok(BodyFraming::Chunked)
+        ));
+        assert!(matches!(
+            BodyFraming::of(&sized),
+            Ok(BodyFraming::Length(5))
+        ));
+        assert!(matches!(
+            BodyFraming::of(&empty),
+            Ok(BodyFraming::Length(0))
+        ));
+        assert!(BodyFraming::of(&unsupported).is_err());
+    }
+
+    #[test]
+    fn forwards_a_sized_body_only() {
+        let upstream = forward(b"helloGET /next HTTP/1.1\r\n\r\n", BodyFraming::Length(5)).unwrap();
+
+        assert_eq!(upstream, b"hello");
+
+        assert!(forward(b"hel", BodyFraming::Length(5)).is_err());
+    }
+
+    #[test]
+    fn forwards_a_chunked_body_only() {
+        let body = b"5;ext=1\r\nhello\r\n\
+            B\r\n, chunked!!\r\n\
+            0\r\n\
+            X-Trailer: yes\r\n\
+            \r\n";
+
+        let input = [&body[..], b"GET /next HTTP/1.1\r\n\r\n"].concat();
+
+        assert_eq!(forward(&input, BodyFraming::Chunked).unwrap(), body);
+    }
+
+    #[test]
+    fn rejects_broken_chunked_bodies() {
+        let bodies: [&[u8]; 4] = [
+            b"5\r\nhel",
+            b"zz\r\nhello\r\n0\r\n\r\n",
+            b"5\r\nhello\r\n0\r\n",
+            b"5\r\nhello",
+        ];
+
+        for body in bodies {
+            assert!(
+                forward(body, BodyFraming::Chunked).is_err(),
+                "{}",
+                String::from_utf8_lossy(body)
+            );
+        }
+    }
+}
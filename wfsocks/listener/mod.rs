@@ -0,0 +1,323 @@
+use std::net::SocketAddr;
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader};
+use tokio::net::{TcpListener, TcpSocket};
+use wfconfig::aux_config::{BindMode, ListenerOptions};
+use wfconfig::parse_args;
+use wfcore::access::AccessControl;
+use wfcore::router::ClientIdentity;
+
+use crate::handshake::within_handshake;
+use crate::http::http_proxy;
+use crate::session::SessionGuard;
+use crate::socks4::socks4_proxy;
+use crate::socks5_proxy;
+use crate::transparent::{bind_tproxy_listener, transparent_proxy};
+
+/* Anything a proxy frontend can talk to, be it TCP or a Unix-domain socket */
+pub trait ClientStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ClientStream for T {}
+
+pub enum ProxyListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+pub struct BoundListener {
+    pub listener: ProxyListener,
+    pub tag: Option<String>,
+}
+
+#[cfg(unix)]
+fn set_v6only(socket: &TcpSocket, v6only: bool) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let value: libc::c_int = v6only as libc::c_int;
+
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_V6ONLY,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+
+    if result != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_v6only(_socket: &TcpSocket, v6only: bool) -> Result<()> {
+    if !v6only {
+        return Err(anyhow!("Dual-stack listeners are only available on Unix"));
+    }
+
+    Ok(())
+}
+
+fn bind_tcp_listener(addr: SocketAddr, dual_stack: bool) -> Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => {
+            let socket = TcpSocket::new_v6()?;
+
+            set_v6only(&socket, !dual_stack)?;
+
+            socket
+        }
+    };
+
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+
+    socket.bind(addr)?;
+
+    Ok(socket.listen(1024)?)
+}
+
+#[cfg(unix)]
+fn bind_unix_listener(path: &str, permissions: &str) -> Result<ProxyListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    /* A socket file left behind by a previous run would make bind() fail */
+    if std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = tokio::net::UnixListener::bind(path)?;
+
+    let mode = u32::from_str_radix(permissions, 8)
+        .map_err(|_| anyhow!("Bad Unix-domain socket permissions {permissions:?}"))?;
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+
+    Ok(ProxyListener::Unix(listener))
+}
+
+#[cfg(not(unix))]
+fn bind_unix_listener(_path: &str, _permissions: &str) -> Result<ProxyListener> {
+    Err(anyhow!("Unix-domain listeners are only available on Unix"))
+}
+
+pub async fn bind_listener(options: &ListenerOptions, mode: &BindMode) -> Result<BoundListener> {
+    let tag = options.tag.clone();
+
+    if let Some(ref path) = options.unix_path {
+        if mode != &BindMode::Proxy {
+            return Err(anyhow!(
+                "Unix-domain listeners are only available in proxy mode"
+            ));
+        }
+
+        let listener = bind_unix_listener(path, &options.unix_permissions)?;
+
+        info!("Socks5/HTTP proxy bound at unix:{path} (tag {tag:?})");
+
+        return Ok(BoundListener { listener, tag });
+    }
+
+    let host = options.host.replace("\"", "");
+
+    let addr = tokio::net::lookup_host((host.as_str(), options.port))
+        .await?
+        .next()
+        .ok_or(anyhow!("No addrs for listener {host}"))?;
+
+    let listener = match mode {
+        BindMode::Tproxy => bind_tproxy_listener(addr)?,
+        _ => bind_tcp_listener(addr, options.dual_stack)?,
+    };
+
+    match mode {
+        BindMode::Proxy => info!("Socks5/HTTP proxy bound at {addr} (tag {tag:?})"),
+        _ => info!("Transparent proxy ({mode:?}) bound at {addr} (tag {tag:?})"),
+    }
+
+    Ok(BoundListener {
+        listener: ProxyListener::Tcp(listener),
+        tag,
+    })
+}
+
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: std::os::unix::io::RawFd = 3;
+
+#[cfg(unix)]
+fn inherit_listener(fd: std::os::unix::io::RawFd) -> Result<ProxyListener> {
+    use std::os::unix::io::FromRawFd;
+
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of_val(&storage) as libc::socklen_t;
+
+    let result =
+        unsafe { libc::getsockname(fd, &mut storage as *mut _ as *mut libc::sockaddr, &mut len) };
+
+    if result != 0 {
+        return Err(anyhow!(
+            "Inherited descriptor {fd} is not a socket: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    if storage.ss_family as libc::c_int == libc::AF_UNIX {
+        let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+
+        listener.set_nonblocking(true)?;
+
+        return Ok(ProxyListener::Unix(tokio::net::UnixListener::from_std(
+            listener,
+        )?));
+    }
+
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+
+    listener.set_nonblocking(true)?;
+
+    Ok(ProxyListener::Tcp(TcpListener::from_std(listener)?))
+}
+
+/* systemd socket activation, names given with FileDescriptorName=
+ * become listener tags. Nothing is inherited unless LISTEN_PID is ours
+ */
+#[cfg(unix)]
+pub fn inherited_listeners() -> Result<Vec<BoundListener>> {
+    let listen_pid = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok());
+
+    if listen_pid != Some(std::process::id()) {
+        return Ok(vec![]);
+    }
+
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<i32>().ok())
+        .unwrap_or(0);
+
+    let names: Vec<String> = std::env::var("LISTEN_FDNAMES")
+        .map(|names| names.split(':').map(String::from).collect())
+        .unwrap_or_default();
+
+    /* Children we spawn must not take the sockets for their own */
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        unsafe {
+            std::env::remove_var(name);
+        }
+    }
+
+    (0..count)
+        .map(|index| {
+            let fd = SD_LISTEN_FDS_START + index;
+
+            let tag = names
+                .get(index as usize)
+                .filter(|name| name.as_str() != "unknown")
+                .cloned();
+
+            let listener = inherit_listener(fd)?;
+
+            info!("Inherited listening socket {fd} from systemd (tag {tag:?})");
+
+            Ok(BoundListener { listener, tag })
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn inherited_listeners() -> Result<Vec<BoundListener>> {
+    Ok(vec![])
+}
+
+/* Every proxy protocol starts with a distinct first byte: SOCKS4 with 4,
+ * SOCKS5 with 5 and HTTP with an uppercase method name
+ */
+pub async fn proxy_client<S: ClientStream + 'static>(
+    client: S,
+    identity: ClientIdentity,
+) -> Result<()> {
+    let mut client = BufReader::new(client);
+
+    let version = match within_handshake(async { Ok(client.fill_buf().await?) }).await? {
+        [version, ..] => *version,
+        [] => return Ok(()),
+    };
+
+    match version {
+        4 => socks4_proxy(client, identity).await,
+        b'A'..=b'Z' => http_proxy(client, identity).await,
+        _ => socks5_proxy(client, identity).await,
+    }
+}
+
+pub async fn serve_listener(bound: BoundListener, mode: BindMode) -> Result<()> {
+    let identity = ClientIdentity {
+        listener: bound.tag,
+        ..Default::default()
+    };
+
+    match bound.listener {
+        ProxyListener::Tcp(listener) => loop {
+            let (client, client_addr) = listener.accept().await?;
+
+            let config = parse_args();
+
+            if !AccessControl::is_allowed(&config, client_addr.ip()) {
+                warn!("Rejecting {client_addr}: denied by the access list");
+
+                continue;
+            }
+
+            let Some(slot) = AccessControl::acquire_slot(&config, client_addr.ip()) else {
+                warn!(
+                    "Rejecting {client_addr}: over {} concurrent connections",
+                    config.access_options.max_connections_per_client
+                );
+
+                continue;
+            };
+
+            let _ = client.set_nodelay(true);
+
+            let mode = mode.clone();
+            let identity = ClientIdentity {
+                client_ip: Some(client_addr.ip()),
+                ..identity.clone()
+            };
+
+            let session = SessionGuard::begin();
+
+            tokio::spawn(async move {
+                let _slot = slot;
+                let _session = session;
+
+                let _ = match mode {
+                    BindMode::Proxy => proxy_client(client, identity).await,
+                    _ => transparent_proxy(client, mode, identity).await,
+                };
+            });
+        },
+        #[cfg(unix)]
+        ProxyListener::Unix(listener) => loop {
+            let (client, _) = listener.accept().await?;
+
+            let identity = identity.clone();
+
+            let session = SessionGuard::begin();
+
+            tokio::spawn(async move {
+                let _session = session;
+
+                let _ = proxy_client(client, identity).await;
+            });
+        },
+    }
+}
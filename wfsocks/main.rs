@@ -1,34 +1,71 @@
+use crate::auth::negotiate_auth;
+use crate::bind::socks5_bind;
+use crate::handshake::{
+    read_request, send_reply, send_reply_addr, within_handshake, ReplyCode, CMD_BIND,
+};
+use crate::listener::ClientStream;
 use crate::pipe::async_pipe::pipe_sockets;
 use crate::pipe::async_udp::pipe_udp;
+use crate::target::{connect_target, relay_after_hello, resolve_target, target_route};
 use log::{error, info};
 use wfcipu::parsers::ip::IpParser;
 use wfconfig::parse_args;
-use wfcore::router::{Router, RouterInterjectionStatus};
-use wfcore::socket::SocketOps;
+use wfcore::router::{ClientIdentity, Router};
 
+mod auth;
+mod bind;
+mod handshake;
+mod http;
+mod listener;
 mod pipe;
+mod session;
+mod socks4;
+mod target;
+mod transparent;
+#[cfg(target_os = "linux")]
+mod tun_stack;
+
+pub use crate::http::http_proxy;
+pub use crate::listener::{
+    bind_listener, inherited_listeners, proxy_client, serve_listener, BoundListener,
+};
+pub use crate::session::{active_sessions, drain_sessions};
+pub use crate::socks4::socks4_proxy;
+pub use crate::transparent::{bind_tproxy_listener, transparent_proxy};
+#[cfg(target_os = "linux")]
+pub use crate::tun_stack::tun_proxy;
+
+#[cfg(not(target_os = "linux"))]
+pub async fn tun_proxy(_config: wfconfig::aux_config::AuxConfig) -> Result<()> {
+    Err(anyhow::anyhow!("TUN mode is only available on Linux"))
+}
 
-use std::net::{IpAddr, SocketAddr};
+use anyhow::Result;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UdpSocket;
 
-use anyhow::{anyhow, Result};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpStream, UdpSocket};
+pub async fn socks5_proxy<S: ClientStream + 'static>(
+    mut client: S,
+    identity: ClientIdentity,
+) -> Result<()> {
+    let config = parse_args();
 
-pub async fn socks5_proxy(mut client: TcpStream) -> Result<()> {
-    let mut buffer = [0; 64];
+    let (identity, request) = within_handshake(async {
+        let identity = negotiate_auth(&mut client, &config, identity).await?;
 
-    client.read(&mut buffer).await?;
-    client.write_all(&[5, 0]).await?;
-    client.read(&mut buffer).await?;
+        Ok((identity, read_request(&mut client).await?))
+    })
+    .await?;
 
-    let config = parse_args();
+    let buffer = request.raw;
 
-    let router_responce = Router::interject_dns(config, &buffer).await?;
+    let parsed_data: IpParser = resolve_target(config, &buffer, &identity).await?;
 
-    let parsed_data: IpParser = match router_responce {
-        RouterInterjectionStatus::Allow => wfdns::parser::parse(&buffer).await?,
-        RouterInterjectionStatus::AutoResolved(parsed) => parsed,
-    };
+    if request.command == CMD_BIND {
+        info!("Got a BIND request");
+
+        return socks5_bind(client, &parsed_data, &identity).await;
+    }
 
     if parsed_data.is_udp {
         info!("Got a UDP associate");
@@ -37,33 +74,12 @@ pub async fn socks5_proxy(mut client: TcpStream) -> Result<()> {
 
         let addr = relay.local_addr()?;
 
-        let mut packet: Vec<u8> = vec![5, 0, 0];
-
-        let ip_type = match addr.ip() {
-            IpAddr::V4(_) => 1u8,
-            IpAddr::V6(_) => 4u8,
-        };
-
-        let ip: Vec<u8> = match addr.ip() {
-            IpAddr::V4(ip) => ip.octets().to_vec(),
-            IpAddr::V6(ip) => ip.octets().to_vec(),
-        };
-
-        let port = match addr {
-            SocketAddr::V4(ip) => ip.port().to_be_bytes().to_vec(),
-            SocketAddr::V6(ip) => ip.port().to_be_bytes().to_vec(),
-        };
-
-        packet.extend_from_slice(&[ip_type]);
-        packet.extend_from_slice(&ip);
-        packet.extend_from_slice(&port);
+        send_reply_addr(&mut client, ReplyCode::Succeeded, addr).await?;
 
-        client.write_all(&packet).await?;
-
-        return pipe_udp(client, relay).await;
+        return pipe_udp(client, relay, identity).await;
     }
 
-    let mut packet = vec![5, 0, 0, parsed_data.dest_addr_type];
+    let mut packet = vec![5, ReplyCode::Succeeded as u8, 0, parsed_data.dest_addr_type];
 
     if parsed_data.dest_addr_type == 3 {
         packet.push(parsed_data.host_unprocessed.len() as u8);
@@ -72,52 +88,34 @@ pub async fn socks5_proxy(mut client: TcpStream) -> Result<()> {
     packet.extend_from_slice(&parsed_data.host_unprocessed);
     packet.extend_from_slice(&parsed_data.port.to_be_bytes());
 
-    if parsed_data.dest_addr_type != 1
-        && parsed_data.dest_addr_type != 3
-        && parsed_data.dest_addr_type != 4
-    {
-        packet[1] = 0x08;
+    let route = target_route(&parsed_data, &identity);
 
+    /* An SNI rule may decide, so the route waits for the ClientHello */
+    if Router::waits_for_sni(&parse_args(), &route).await? {
         client.write_all(&packet).await?;
 
-        return Err(anyhow!(
-            "Unknown destination type: {}",
-            &parsed_data.dest_addr_type
-        ));
+        return relay_after_hello(client, vec![], &parsed_data, route).await;
     }
 
-    let sock_addr = match parsed_data.host_raw.len() {
-        4 => {
-            let ip_bytes: [u8; 4] = unsafe { *(parsed_data.host_raw.as_ptr() as *const [u8; 4]) };
-
-            SocketAddr::new(ip_bytes.into(), parsed_data.port)
-        }
-        16 => {
-            let ip_bytes: [u8; 16] = unsafe { *(parsed_data.host_raw.as_ptr() as *const [u8; 16]) };
-
-            SocketAddr::new(ip_bytes.into(), parsed_data.port)
-        }
-        _ => return Err(anyhow!("No IP")),
-    };
-
-    let server_socket = SocketOps::connect_socket(
-        String::from_utf8_lossy(&parsed_data.host_unprocessed).to_string(),
-        sock_addr,
-    )
-    .await;
+    let server_socket = connect_target(&parsed_data, route).await;
 
     match server_socket {
-        Ok(socket) => {
+        Ok((socket, policy)) => {
             client.write_all(&packet).await?;
 
             drop(packet);
 
-            tokio::spawn(async move {
-                let _ = pipe_sockets(client, socket).await;
-            });
+            let _ = pipe_sockets(client, socket, policy).await;
         }
         Err(error) => {
-            error!("Connection aborted: {error} with an address {sock_addr}");
+            let code = ReplyCode::from_error(&error);
+
+            error!(
+                "Connection aborted: {error} with a host {}, replying {code:?}",
+                String::from_utf8_lossy(&parsed_data.host_unprocessed)
+            );
+
+            send_reply(&mut client, code).await?;
         }
     }
 
@@ -1,52 +1,136 @@
 use std::time::Duration;
 
 use tokio::net::TcpStream;
+use tokio::time::Instant;
 
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use anyhow::{anyhow, Result};
+use log::debug;
 
 use wfacs5ch::client_hook;
 use wfblmark;
 use wfblmark::remove_marker;
+use wfconfig::aux_config::{RelayMode, SocketOptions};
 use wfconfig::parse_args;
+use wfcore::relay::RelayPolicy;
+use wfcore::shaping::Shaper;
+
+const LARGE_READ: usize = 16 * 1024;
+
+#[cfg(target_os = "linux")]
+use crate::pipe::splice_pipe::{splice_end, splice_sockets, SpliceEnd};
+#[cfg(unix)]
+use crate::pipe::threaded_pipe::{into_client_fd, pipe_threaded};
+
+/* The relay only counts as idle once both directions have been silent for
+ * longer than their own timeouts, so a one-way bulk transfer keeps it alive
+ */
+pub(crate) fn idle_deadline(
+    last_upload: Instant,
+    upload_idle: Duration,
+    last_download: Instant,
+    download_idle: Duration,
+) -> Option<Instant> {
+    let upload = last_upload.checked_add(upload_idle)?;
+    let download = last_download.checked_add(download_idle)?;
+
+    Some(upload.max(download))
+}
+
+/* Waits out the rate limits and, after a large read, lets other connections
+ * on this worker run, so one bulk download can't starve interactive ones
+ */
+pub(crate) async fn share_bandwidth(shaper: &Shaper, bytes: usize) {
+    shaper.throttle(bytes).await;
+
+    if bytes >= LARGE_READ {
+        tokio::task::yield_now().await;
+    }
+}
 
 #[allow(unused_assignments)]
-pub async fn pipe_sockets(socket: TcpStream, stream: TcpStream) -> Result<()> {
+pub async fn pipe_sockets<S>(mut socket: S, stream: TcpStream, policy: RelayPolicy) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + 'static,
+{
     let mut socket_open = true;
     let mut stream_open = true;
 
     let config = parse_args();
 
-    let socket: std::net::TcpStream = socket.into_std()?;
+    #[cfg(unix)]
+    if policy.mode.unwrap_or(config.socket_options.relay_mode) == RelayMode::Threaded {
+        match into_client_fd(socket)? {
+            Ok((client, leftover)) => {
+                return pipe_threaded(client, leftover, stream, policy).await;
+            }
+            Err(unsupported) => {
+                debug!("This client can't be relayed by a thread, staying async");
+
+                socket = unsupported;
+            }
+        }
+    }
+
+    let RelayPolicy {
+        shaper,
+        profile,
+        lease: _lease,
+        ..
+    } = policy;
+
     let stream: std::net::TcpStream = stream.into_std()?;
 
-    socket.set_nodelay(true)?;
     stream.set_nodelay(true)?;
-
-    socket.set_nonblocking(true)?;
     stream.set_nonblocking(true)?;
 
-    let mut socket = TcpStream::from_std(socket)?;
     let mut stream = TcpStream::from_std(stream)?;
 
     let mut buffer1: Vec<u8> = vec![0u8; config.socket_options.so_send_size];
     let mut buffer2: Vec<u8> = vec![0u8; config.socket_options.so_recv_size];
 
     let mut transferred = 0;
+    let mut hops = 0;
     let mut connection_marked: bool = false;
     let mut t16kb_proven = false;
 
+    let upload_idle = SocketOptions::timeout(config.socket_options.upload_idle_timeout);
+    let download_idle = SocketOptions::timeout(config.socket_options.download_idle_timeout);
+
+    let mut last_upload = Instant::now();
+    let mut last_download = Instant::now();
+
     loop {
         if !socket_open || !stream_open {
             break;
         }
 
-        tokio::select! {
-            readable = socket.readable(), if socket_open => {
-                readable?;
+        /* Past the desync hops and the 16-32kb heuristic window there's nothing
+         * left to do in userspace, unless patterns still rewrite the traffic
+         */
+        #[cfg(target_os = "linux")]
+        if hops >= config.desync_options.packet_hop
+            && transferred >= 33 * 1024
+            && config.pattern_options.patterns.is_empty()
+        {
+            if let Some(client) = splice_end(&socket) {
+                return splice_sockets(
+                    client,
+                    SpliceEnd::Tcp(&stream),
+                    &shaper,
+                    upload_idle,
+                    download_idle,
+                )
+                .await;
+            }
+        }
 
-                match socket.try_read(&mut buffer1) {
+        let idle_deadline = idle_deadline(last_upload, upload_idle, last_download, download_idle);
+
+        tokio::select! {
+            read = socket.read(&mut buffer1), if socket_open => {
+                match read {
                     Ok(0) => {
                         socket_open = false;
 
@@ -56,14 +140,17 @@ pub async fn pipe_sockets(socket: TcpStream, stream: TcpStream) -> Result<()> {
                     }
 
                     Ok(n) => {
+                        last_upload = Instant::now();
+                        hops += 1;
+
                         let data = &buffer1[..n];
 
-                        let transformed = client_hook(&mut stream, data).await?;
+                        let transformed = client_hook(&mut stream, data, profile.as_deref()).await?;
 
                         stream.write_all(&transformed).await?;
-                    }
 
-                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => { }
+                        share_bandwidth(&shaper, n).await;
+                    }
 
                     Err(e) => return Err(e.into())
                 }
@@ -75,6 +162,9 @@ pub async fn pipe_sockets(socket: TcpStream, stream: TcpStream) -> Result<()> {
 
                 return Err(anyhow!("16-32kb block detected"));
             }
+            _ = tokio::time::sleep_until(idle_deadline.unwrap_or_else(Instant::now)), if idle_deadline.is_some() => {
+                return Err(anyhow!("Connection has been idle for too long"));
+            }
             readable1 = stream.readable(), if stream_open => {
                 readable1?;
 
@@ -88,10 +178,14 @@ pub async fn pipe_sockets(socket: TcpStream, stream: TcpStream) -> Result<()> {
                     }
 
                     Ok(n) => {
+                        last_download = Instant::now();
+
                         let data = &buffer2[..n];
 
                         socket.write_all(data).await?;
 
+                        share_bandwidth(&shaper, n).await;
+
                         transferred += n;
 
                         if connection_marked {
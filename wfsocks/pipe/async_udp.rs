@@ -5,10 +5,21 @@ use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::io::AsyncReadExt;
-use tokio::net::{TcpStream, UdpSocket};
+use tokio::net::UdpSocket;
 use tokio::sync::Mutex;
+use wfconfig::parse_args;
+use wfcore::router::ClientIdentity;
 
-fn parse_ipdata(buffer: &[u8]) -> Option<(SocketAddr, usize)> {
+use crate::listener::ClientStream;
+use crate::target::{resolve_target, target_addr};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum UdpDestination {
+    Addr(SocketAddr),
+    Domain(String, u16),
+}
+
+fn parse_ipdata(buffer: &[u8]) -> Option<(UdpDestination, usize)> {
     match buffer[3] {
         1 => {
             if buffer.len() < 10 {
@@ -20,7 +31,23 @@ fn parse_ipdata(buffer: &[u8]) -> Option<(SocketAddr, usize)> {
                 u16::from_be_bytes([buffer[8], buffer[9]]),
             );
 
-            Some((addr, 10))
+            Some((UdpDestination::Addr(addr), 10))
+        }
+        3 => {
+            if buffer.len() < 5 {
+                return None;
+            }
+
+            let domain_end = 5 + buffer[4] as usize;
+
+            if buffer.len() < domain_end + 2 {
+                return None;
+            }
+
+            let domain = String::from_utf8_lossy(&buffer[5..domain_end]).to_string();
+            let port = u16::from_be_bytes([buffer[domain_end], buffer[domain_end + 1]]);
+
+            Some((UdpDestination::Domain(domain, port), domain_end + 2))
         }
         4 => {
             if buffer.len() < 22 {
@@ -36,7 +63,7 @@ fn parse_ipdata(buffer: &[u8]) -> Option<(SocketAddr, usize)> {
                 u16::from_be_bytes([buffer[20], buffer[21]]),
             );
 
-            Some((addr, 22))
+            Some((UdpDestination::Addr(addr), 22))
         }
         _ => {
             return None;
@@ -44,15 +71,15 @@ fn parse_ipdata(buffer: &[u8]) -> Option<(SocketAddr, usize)> {
     }
 }
 
-fn parse_udp_msg(buffer: &[u8]) -> Option<(SocketAddr, &[u8])> {
+fn parse_udp_msg(buffer: &[u8]) -> Option<(u8, UdpDestination, &[u8])> {
     if buffer.len() < 4 {
         debug!("Buffer shortage {}", buffer.len());
 
         return None;
     }
 
-    if buffer[0..3] != [0, 0, 0] {
-        debug!("Received frag {:?}", &buffer[0..3]);
+    if buffer[0..2] != [0, 0] {
+        debug!("Received non-zero RSV {:?}", &buffer[0..2]);
 
         return None;
     }
@@ -61,40 +88,196 @@ fn parse_udp_msg(buffer: &[u8]) -> Option<(SocketAddr, &[u8])> {
 
     let data = &buffer[data_start..];
 
-    Some((addr, data))
+    Some((buffer[2], addr, data))
+}
+
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+const REASSEMBLY_MAX_SIZE: usize = 65535;
+
+const FRAG_END_OF_SEQUENCE: u8 = 0x80;
+
+struct Reassembly {
+    dest: UdpDestination,
+    position: u8,
+    data: Vec<u8>,
+    started: tokio::time::Instant,
+}
+
+impl Reassembly {
+    fn is_expired(&self) -> bool {
+        self.started.elapsed() > REASSEMBLY_TIMEOUT
+    }
+}
+
+/* RFC 1928 section 7: a fragment sequence is abandoned on timeout or
+ * whenever a fragment arrives out of order, and the first fragment of a
+ * new one always starts over
+ */
+fn reassemble(
+    queue: &mut Option<Reassembly>,
+    frag: u8,
+    dest: UdpDestination,
+    data: &[u8],
+) -> Option<(UdpDestination, Vec<u8>)> {
+    let position = frag & !FRAG_END_OF_SEQUENCE;
+    let is_last = frag & FRAG_END_OF_SEQUENCE != 0;
+
+    let expected = match queue {
+        Some(ref reassembly) if !reassembly.is_expired() && reassembly.dest == dest => {
+            reassembly.position + 1
+        }
+        _ => 1,
+    };
+
+    if position == 1 {
+        if expected != 1 {
+            debug!("Restarting a fragment sequence, got 1 while expecting {expected}");
+        }
+
+        *queue = None;
+    } else if position != expected {
+        debug!("Abandoning a fragment sequence, got {position} while expecting {expected}");
+
+        *queue = None;
+
+        return None;
+    }
+
+    let reassembly = queue.get_or_insert_with(|| Reassembly {
+        dest,
+        position: 0,
+        data: vec![],
+        started: tokio::time::Instant::now(),
+    });
+
+    reassembly.position = position;
+    reassembly.data.extend_from_slice(data);
+
+    if reassembly.data.len() > REASSEMBLY_MAX_SIZE {
+        debug!("Abandoning a fragment sequence larger than {REASSEMBLY_MAX_SIZE} bytes");
+
+        *queue = None;
+
+        return None;
+    }
+
+    if !is_last {
+        return None;
+    }
+
+    queue
+        .take()
+        .map(|reassembly| (reassembly.dest, reassembly.data))
 }
 
-fn wrap_udp_response(data: &[u8], dest: SocketAddr) -> Vec<u8> {
-    let mut response = Vec::with_capacity(25 + data.len());
+/* Replies carry the destination in the same form the client has used */
+fn wrap_udp_response(data: &[u8], dest: &UdpDestination) -> Vec<u8> {
+    let mut response = Vec::with_capacity(262 + data.len());
 
     response.extend_from_slice(&[0, 0, 0]);
 
-    match dest {
-        SocketAddr::V4(addr) => {
+    let port = match dest {
+        UdpDestination::Addr(SocketAddr::V4(addr)) => {
             response.push(1);
             response.extend_from_slice(&addr.ip().octets());
+
+            addr.port()
         }
-        SocketAddr::V6(addr) => {
+        UdpDestination::Addr(SocketAddr::V6(addr)) => {
             response.push(4);
             response.extend_from_slice(&addr.ip().octets());
+
+            addr.port()
         }
-    }
+        UdpDestination::Domain(domain, port) => {
+            response.push(3);
+            response.push(domain.len() as u8);
+            response.extend_from_slice(domain.as_bytes());
 
-    response.extend_from_slice(&dest.port().to_be_bytes());
+            *port
+        }
+    };
+
+    response.extend_from_slice(&port.to_be_bytes());
     response.extend_from_slice(data);
 
     response
 }
 
+const PENDING_MAX_DATAGRAMS: usize = 64;
+
+type DnsCache = HashMap<String, IpAddr>;
+
+/* Datagrams waiting for their domain to resolve, in the order they came in */
+type PendingResolutions = HashMap<String, Vec<(SocketAddr, UdpDestination, Vec<u8>)>>;
+
+fn cached_destination(dns_cache: &DnsCache, dest: &UdpDestination) -> Option<SocketAddr> {
+    match dest {
+        UdpDestination::Addr(addr) => Some(*addr),
+        UdpDestination::Domain(domain, port) => {
+            dns_cache.get(domain).map(|ip| SocketAddr::new(*ip, *port))
+        }
+    }
+}
+
+async fn resolve_destination(domain: &str, port: u16, identity: &ClientIdentity) -> Result<IpAddr> {
+    let mut buffer = vec![5, 3, 0, 3, domain.len() as u8];
+
+    buffer.extend_from_slice(domain.as_bytes());
+    buffer.extend_from_slice(&port.to_be_bytes());
+
+    let parsed_data = resolve_target(parse_args(), &buffer, identity).await?;
+
+    Ok(target_addr(&parsed_data)?.ip())
+}
+
+/* Only the first datagram to a domain starts resolving it, the ones after
+ * it queue up so they all leave in order once it's done
+ */
+fn queue_for_resolution(
+    pending: &mut PendingResolutions,
+    client_addr: SocketAddr,
+    dest: UdpDestination,
+    data: Vec<u8>,
+) -> bool {
+    let UdpDestination::Domain(ref domain, _) = dest else {
+        return false;
+    };
+
+    match pending.get_mut(domain) {
+        Some(queue) if queue.len() >= PENDING_MAX_DATAGRAMS => {
+            debug!("Dropping a datagram to {dest:?}, too many are waiting for it to resolve");
+
+            false
+        }
+        Some(queue) => {
+            queue.push((client_addr, dest, data));
+
+            false
+        }
+        None => {
+            pending.insert(domain.clone(), vec![(client_addr, dest, data)]);
+
+            true
+        }
+    }
+}
+
+type Flows = HashMap<(SocketAddr, UdpDestination), Relay>;
+
 struct Relay {
     upstream: Arc<UdpSocket>,
     last_used: tokio::time::Instant,
 }
 
-pub async fn pipe_udp(control: TcpStream, relay: UdpSocket) -> Result<()> {
+pub async fn pipe_udp<S: ClientStream + 'static>(
+    control: S,
+    relay: UdpSocket,
+    identity: ClientIdentity,
+) -> Result<()> {
     let relay = Arc::new(relay);
 
-    let relays = Arc::new(Mutex::new(HashMap::<(SocketAddr, SocketAddr), Relay>::new()));
+    let relays = Arc::new(Mutex::new(Flows::new()));
 
     let (shutdown_tx, mut shutdown_rx) = tokio::sync::mpsc::channel(1);
 
@@ -128,14 +311,59 @@ pub async fn pipe_udp(control: TcpStream, relay: UdpSocket) -> Result<()> {
             tokio::spawn(async move {
                 let mut buf = [0; 65535];
 
+                let mut fragments = HashMap::<SocketAddr, Option<Reassembly>>::new();
+                let mut dns_cache = DnsCache::new();
+                let mut pending = PendingResolutions::new();
+
+                let (resolved_tx, mut resolved_rx) = tokio::sync::mpsc::unbounded_channel();
+
                 loop {
                     tokio::select! {
                         result = relay.recv_from(&mut buf) => {
                             match result {
                                 Ok((size, client_addr)) => {
-                                    if let Some((dest_addr, data)) = parse_udp_msg(&buf[..size]) {
-                                        handle_udp_packet(&relay, &relays, client_addr, dest_addr, data).await;
+                                    let Some((frag, dest, data)) = parse_udp_msg(&buf[..size]) else {
+                                        continue;
+                                    };
+
+                                    let (dest, data) = if frag == 0 {
+                                        (dest, data.to_vec())
+                                    } else {
+                                        fragments.retain(|_, queue| {
+                                            queue.as_ref().is_some_and(|reassembly| !reassembly.is_expired())
+                                        });
+
+                                        let queue = fragments.entry(client_addr).or_default();
+
+                                        match reassemble(queue, frag, dest, data) {
+                                            Some(reassembled) => reassembled,
+                                            None => continue,
+                                        }
+                                    };
+
+                                    if let Some(dest_addr) = cached_destination(&dns_cache, &dest) {
+                                        handle_udp_packet(&relay, &relays, client_addr, dest, dest_addr, &data).await;
+
+                                        continue;
                                     }
+
+                                    let UdpDestination::Domain(domain, port) = dest.clone() else {
+                                        continue;
+                                    };
+
+                                    if !queue_for_resolution(&mut pending, client_addr, dest, data) {
+                                        continue;
+                                    }
+
+                                    /* Other destinations keep flowing while this domain resolves */
+                                    let resolved_tx = resolved_tx.clone();
+                                    let identity = identity.clone();
+
+                                    tokio::spawn(async move {
+                                        let resolved = resolve_destination(&domain, port, &identity).await;
+
+                                        let _ = resolved_tx.send((domain, resolved));
+                                    });
                                 }
 
                                 Err(e) => {
@@ -145,6 +373,28 @@ pub async fn pipe_udp(control: TcpStream, relay: UdpSocket) -> Result<()> {
                                 }
                             }
                         }
+                        Some((domain, resolved)) = resolved_rx.recv() => {
+                            let queue = pending.remove(&domain).unwrap_or_default();
+
+                            let ip = match resolved {
+                                Ok(ip) => ip,
+                                Err(e) => {
+                                    error!("Dropping {} datagrams to {domain}: {e}", queue.len());
+
+                                    continue;
+                                }
+                            };
+
+                            dns_cache.insert(domain, ip);
+
+                            for (client_addr, dest, data) in queue {
+                                let UdpDestination::Domain(_, port) = dest else {
+                                    continue;
+                                };
+
+                                handle_udp_packet(&relay, &relays, client_addr, dest, SocketAddr::new(ip, port), &data).await;
+                            }
+                        }
                         _ = shutdown_rx.recv() => {
                             break;
                         }
@@ -178,12 +428,13 @@ pub async fn pipe_udp(control: TcpStream, relay: UdpSocket) -> Result<()> {
 
 async fn handle_udp_packet(
     relay: &Arc<UdpSocket>,
-    flows: &Arc<Mutex<HashMap<(SocketAddr, SocketAddr), Relay>>>,
+    flows: &Arc<Mutex<Flows>>,
     client_addr: SocketAddr,
+    dest: UdpDestination,
     dest_addr: SocketAddr,
     data: &[u8],
 ) {
-    let key = (client_addr, dest_addr);
+    let key = (client_addr, dest.clone());
 
     let mut flows_guard = flows.lock().await;
 
@@ -199,7 +450,7 @@ async fn handle_udp_packet(
             }
         }
     } else {
-        match create_new_relay_controller(relay, client_addr, dest_addr).await {
+        match create_new_relay_controller(relay, client_addr, dest, dest_addr).await {
             Ok(flow) => match flow.upstream.send_to(data, dest_addr).await {
                 Ok(_sent) => {
                     flows_guard.insert(key, flow);
@@ -218,9 +469,16 @@ async fn handle_udp_packet(
 async fn create_new_relay_controller(
     relay: &Arc<UdpSocket>,
     client_addr: SocketAddr,
+    dest: UdpDestination,
     dest_addr: SocketAddr,
 ) -> Result<Relay> {
-    let upstream = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    /* The flow keeps sending to the family it was resolved to first */
+    let bind_addr = match dest_addr {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+
+    let upstream = Arc::new(UdpSocket::bind(bind_addr).await?);
 
     let flow = Relay {
         upstream: upstream.clone(),
@@ -235,7 +493,7 @@ async fn create_new_relay_controller(
         loop {
             match upstream.recv_from(&mut buf).await {
                 Ok((size, _src_addr)) => {
-                    let response = wrap_udp_response(&buf[..size], dest_addr);
+                    let response = wrap_udp_response(&buf[..size], &dest);
 
                     match relay.send_to(&response, client_addr).await {
                         Ok(_sent) => {}
@@ -256,3 +514,171 @@ async fn create_new_relay_controller(
 
     Ok(flow)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dest() -> UdpDestination {
+        UdpDestination::Domain("example.com".to_string(), 53)
+    }
+
+    #[test]
+    fn datagrams_queue_behind_a_pending_resolution() {
+        let mut pending = PendingResolutions::new();
+
+        let client: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let other = UdpDestination::Domain("example.org".to_string(), 53);
+
+        assert!(queue_for_resolution(
+            &mut pending,
+            client,
+            dest(),
+            b"1".to_vec()
+        ));
+        assert!(!queue_for_resolution(
+            &mut pending,
+            client,
+            dest(),
+            b"2".to_vec()
+        ));
+        assert!(queue_for_resolution(
+            &mut pending,
+            client,
+            other,
+            b"a".to_vec()
+        ));
+        assert!(!queue_for_resolution(
+            &mut pending,
+            client,
+            dest(),
+            b"3".to_vec()
+        ));
+
+        let queued: Vec<Vec<u8>> = pending["example.com"]
+            .iter()
+            .map(|(_, _, data)| data.clone())
+            .collect();
+
+        assert_eq!(queued, [b"1", b"2", b"3"]);
+        assert_eq!(pending["example.org"].len(), 1);
+    }
+
+    #[test]
+    fn pending_queue_is_bounded() {
+        let mut pending = PendingResolutions::new();
+
+        let client: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+
+        for n in 0..PENDING_MAX_DATAGRAMS + 8 {
+            queue_for_resolution(&mut pending, client, dest(), vec![n as u8]);
+        }
+
+        assert_eq!(pending["example.com"].len(), PENDING_MAX_DATAGRAMS);
+        assert_eq!(
+            pending["example.com"].last().unwrap().2,
+            [PENDING_MAX_DATAGRAMS as u8 - 1]
+        );
+    }
+
+    #[test]
+    fn addresses_never_wait() {
+        let mut pending = PendingResolutions::new();
+
+        let client: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let dest = UdpDestination::Addr("192.0.2.1:53".parse().unwrap());
+
+        assert!(!queue_for_resolution(
+            &mut pending,
+            client,
+            dest,
+            b"1".to_vec()
+        ));
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn reassembles_in_order() {
+        let mut queue = None;
+
+        assert_eq!(reassemble(&mut queue, 1, dest(), b"ab"), None);
+        assert_eq!(reassemble(&mut queue, 2, dest(), b"cd"), None);
+        assert_eq!(
+            reassemble(&mut queue, 3 | FRAG_END_OF_SEQUENCE, dest(), b"ef"),
+            Some((dest(), b"abcdef".to_vec()))
+        );
+        assert!(queue.is_none());
+    }
+
+    #[test]
+    fn abandons_out_of_order() {
+        let mut queue = None;
+
+        assert_eq!(reassemble(&mut queue, 1, dest(), b"ab"), None);
+        assert_eq!(reassemble(&mut queue, 3, dest(), b"ef"), None);
+        assert!(queue.is_none());
+
+        assert_eq!(
+            reassemble(&mut queue, 2 | FRAG_END_OF_SEQUENCE, dest(), b"cd"),
+            None
+        );
+    }
+
+    #[test]
+    fn first_fragment_restarts_sequence() {
+        let mut queue = None;
+
+        assert_eq!(reassemble(&mut queue, 1, dest(), b"old"), None);
+        assert_eq!(reassemble(&mut queue, 2, dest(), b"old"), None);
+        assert_eq!(reassemble(&mut queue, 1, dest(), b"ab"), None);
+        assert_eq!(
+            reassemble(&mut queue, 2 | FRAG_END_OF_SEQUENCE, dest(), b"cd"),
+            Some((dest(), b"abcd".to_vec()))
+        );
+    }
+
+    #[test]
+    fn first_fragment_to_another_destination_restarts_sequence() {
+        let other = UdpDestination::Addr("127.0.0.1:53".parse().unwrap());
+
+        let mut queue = None;
+
+        assert_eq!(reassemble(&mut queue, 1, dest(), b"old"), None);
+        assert_eq!(reassemble(&mut queue, 1, other.clone(), b"ab"), None);
+        assert_eq!(
+            reassemble(&mut queue, 2 | FRAG_END_OF_SEQUENCE, other.clone(), b"cd"),
+            Some((other, b"abcd".to_vec()))
+        );
+    }
+
+    #[test]
+    fn abandons_expired_sequence() {
+        let mut queue = None;
+
+        assert_eq!(reassemble(&mut queue, 1, dest(), b"ab"), None);
+
+        if let Some(ref mut reassembly) = queue {
+            reassembly.started = tokio::time::Instant::now() - REASSEMBLY_TIMEOUT * 2;
+        }
+
+        assert_eq!(
+            reassemble(&mut queue, 2 | FRAG_END_OF_SEQUENCE, dest(), b"cd"),
+            None
+        );
+        assert!(queue.is_none());
+    }
+
+    #[test]
+    fn abandons_oversized_sequence() {
+        let chunk = vec![0u8; REASSEMBLY_MAX_SIZE / 2 + 1];
+
+        let mut queue = None;
+
+        assert_eq!(reassemble(&mut queue, 1, dest(), &chunk), None);
+        assert_eq!(
+            reassemble(&mut queue, 2 | FRAG_END_OF_SEQUENCE, dest(), &chunk),
+            None
+        );
+        assert!(queue.is_none());
+    }
+}
@@ -1,2 +1,6 @@
 pub mod async_pipe;
 pub mod async_udp;
+#[cfg(target_os = "linux")]
+pub mod splice_pipe;
+#[cfg(unix)]
+pub mod threaded_pipe;
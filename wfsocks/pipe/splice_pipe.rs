@@ -0,0 +1,243 @@
+use std::any::Any;
+use std::io::ErrorKind;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::debug;
+use tokio::io::{BufReader, Interest};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::time::Instant;
+
+use wfcore::shaping::Shaper;
+
+use crate::pipe::async_pipe::{idle_deadline, share_bandwidth};
+
+const SPLICE_CHUNK: usize = 64 * 1024;
+
+/* Only plain kernel sockets can be spliced, so anything else, like a TUN
+ * stream, keeps going through the userspace loop
+ */
+pub enum SpliceEnd<'a> {
+    Tcp(&'a TcpStream),
+    Unix(&'a UnixStream),
+}
+
+impl SpliceEnd<'_> {
+    fn fd(&self) -> RawFd {
+        match self {
+            SpliceEnd::Tcp(stream) => stream.as_raw_fd(),
+            SpliceEnd::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+
+    async fn ready(&self, interest: Interest) -> std::io::Result<()> {
+        match self {
+            SpliceEnd::Tcp(stream) => stream.ready(interest).await.map(|_| ()),
+            SpliceEnd::Unix(stream) => stream.ready(interest).await.map(|_| ()),
+        }
+    }
+
+    fn try_io<R>(
+        &self,
+        interest: Interest,
+        f: impl FnOnce() -> std::io::Result<R>,
+    ) -> std::io::Result<R> {
+        match self {
+            SpliceEnd::Tcp(stream) => stream.try_io(interest, f),
+            SpliceEnd::Unix(stream) => stream.try_io(interest, f),
+        }
+    }
+}
+
+/* Data still sitting in a BufReader would be skipped by splice, such a
+ * client can only be spliced once its buffer has been drained
+ */
+pub fn splice_end<S: 'static>(socket: &S) -> Option<SpliceEnd<'_>> {
+    let socket = socket as &dyn Any;
+
+    if let Some(stream) = socket.downcast_ref::<TcpStream>() {
+        return Some(SpliceEnd::Tcp(stream));
+    }
+
+    if let Some(stream) = socket.downcast_ref::<UnixStream>() {
+        return Some(SpliceEnd::Unix(stream));
+    }
+
+    if let Some(reader) = socket.downcast_ref::<BufReader<TcpStream>>() {
+        return reader
+            .buffer()
+            .is_empty()
+            .then(|| SpliceEnd::Tcp(reader.get_ref()));
+    }
+
+    if let Some(reader) = socket.downcast_ref::<BufReader<UnixStream>>() {
+        return reader
+            .buffer()
+            .is_empty()
+            .then(|| SpliceEnd::Unix(reader.get_ref()));
+    }
+
+    None
+}
+
+struct Pipe {
+    read: RawFd,
+    write: RawFd,
+}
+
+impl Pipe {
+    fn new() -> Result<Pipe> {
+        let mut fds = [0 as libc::c_int; 2];
+
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        Ok(Pipe {
+            read: fds[0],
+            write: fds[1],
+        })
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> std::io::Result<usize> {
+    let result = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(result as usize)
+}
+
+/* One direction of the relay. Bytes already moved into the pipe are tracked
+ * outside of the future, so select! dropping a half-done pump loses nothing
+ */
+struct Direction {
+    pipe: Pipe,
+    pending: usize,
+}
+
+impl Direction {
+    fn new() -> Result<Direction> {
+        Ok(Direction {
+            pipe: Pipe::new()?,
+            pending: 0,
+        })
+    }
+
+    /* Moves one chunk from `from` to `to`, 0 means EOF */
+    async fn pump(&mut self, from: &SpliceEnd<'_>, to: &SpliceEnd<'_>) -> Result<usize> {
+        if self.pending == 0 {
+            let moved = loop {
+                from.ready(Interest::READABLE).await?;
+
+                match from.try_io(Interest::READABLE, || {
+                    splice(from.fd(), self.pipe.write, SPLICE_CHUNK)
+                }) {
+                    Ok(moved) => break moved,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e.into()),
+                }
+            };
+
+            self.pending = moved;
+        }
+
+        let moved = self.pending;
+
+        while self.pending > 0 {
+            to.ready(Interest::WRITABLE).await?;
+
+            match to.try_io(Interest::WRITABLE, || {
+                splice(self.pipe.read, to.fd(), self.pending)
+            }) {
+                Ok(written) => self.pending -= written,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(moved)
+    }
+}
+
+fn shutdown_write(end: &SpliceEnd<'_>) {
+    unsafe {
+        libc::shutdown(end.fd(), libc::SHUT_WR);
+    }
+}
+
+/* Same lifecycle as the userspace loop: the relay ends as soon as either
+ * side closes, with the other side getting a FIN
+ */
+pub async fn splice_sockets(
+    client: SpliceEnd<'_>,
+    stream: SpliceEnd<'_>,
+    shaper: &Shaper,
+    upload_idle: Duration,
+    download_idle: Duration,
+) -> Result<()> {
+    let mut upload = Direction::new()?;
+    let mut download = Direction::new()?;
+
+    let mut last_upload = Instant::now();
+    let mut last_download = Instant::now();
+
+    debug!("Switching the connection to the splice relay");
+
+    loop {
+        let idle_deadline = idle_deadline(last_upload, upload_idle, last_download, download_idle);
+
+        tokio::select! {
+            moved = upload.pump(&client, &stream) => {
+                let moved = moved?;
+
+                if moved == 0 {
+                    shutdown_write(&stream);
+
+                    return Ok(());
+                }
+
+                last_upload = Instant::now();
+
+                share_bandwidth(shaper, moved).await;
+            }
+            moved = download.pump(&stream, &client) => {
+                let moved = moved?;
+
+                if moved == 0 {
+                    shutdown_write(&client);
+
+                    return Ok(());
+                }
+
+                last_download = Instant::now();
+
+                share_bandwidth(shaper, moved).await;
+            }
+            _ = tokio::time::sleep_until(idle_deadline.unwrap_or_else(Instant::now)), if idle_deadline.is_some() => {
+                return Err(anyhow!("Connection has been idle for too long"));
+            }
+        }
+    }
+}
@@ -0,0 +1,387 @@
+use std::any::Any;
+use std::io::ErrorKind;
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use log::debug;
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::runtime::Handle;
+use tokio::sync::oneshot;
+use wfacs5ch::client_hook;
+use wfconfig::aux_config::{AuxConfig, SocketOptions};
+use wfconfig::parse_args;
+use wfcore::relay::RelayPolicy;
+
+/* 2^14 bytes of plaintext plus the largest expansion TLS allows */
+const MAX_TLS_RECORD: usize = 16384 + 2048;
+const TLS_HEADER: usize = 5;
+
+/* The thread owns plain descriptors, so only kernel sockets qualify. Whatever
+ * the handshake left in a BufReader goes to the server first
+ */
+pub fn into_client_fd<S: 'static>(socket: S) -> Result<std::result::Result<(OwnedFd, Vec<u8>), S>> {
+    let socket: Box<dyn Any> = Box::new(socket);
+
+    let socket = match socket.downcast::<TcpStream>() {
+        Ok(stream) => return Ok(Ok((stream.into_std()?.into(), vec![]))),
+        Err(socket) => socket,
+    };
+
+    let socket = match socket.downcast::<UnixStream>() {
+        Ok(stream) => return Ok(Ok((stream.into_std()?.into(), vec![]))),
+        Err(socket) => socket,
+    };
+
+    let socket = match socket.downcast::<BufReader<TcpStream>>() {
+        Ok(reader) => {
+            let leftover = reader.buffer().to_vec();
+
+            return Ok(Ok((reader.into_inner().into_std()?.into(), leftover)));
+        }
+        Err(socket) => socket,
+    };
+
+    let socket = match socket.downcast::<BufReader<UnixStream>>() {
+        Ok(reader) => {
+            let leftover = reader.buffer().to_vec();
+
+            return Ok(Ok((reader.into_inner().into_std()?.into(), leftover)));
+        }
+        Err(socket) => socket,
+    };
+
+    Ok(Err(*socket.downcast::<S>().unwrap()))
+}
+
+fn looks_like_tls(data: &[u8]) -> bool {
+    data.len() >= 2 && (20..=23).contains(&data[0]) && data[1] == 3
+}
+
+fn poll_timeout(timeout: Option<Duration>) -> libc::c_int {
+    match timeout {
+        Some(timeout) => timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int,
+        None => -1,
+    }
+}
+
+fn poll_fds(fds: &mut [libc::pollfd], timeout: Option<Duration>) -> std::io::Result<usize> {
+    loop {
+        let result = unsafe {
+            libc::poll(
+                fds.as_mut_ptr(),
+                fds.len() as libc::nfds_t,
+                poll_timeout(timeout),
+            )
+        };
+
+        if result >= 0 {
+            return Ok(result as usize);
+        }
+
+        let error = std::io::Error::last_os_error();
+
+        if error.kind() != ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+fn read_fd(fd: RawFd, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let result = unsafe { libc::read(fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(result as usize)
+}
+
+/* The descriptors stay non-blocking, since the desync hooks borrow the
+ * server socket as a tokio stream, so a full buffer is waited out with poll
+ */
+fn write_all_fd(fd: RawFd, mut data: &[u8], timeout: Option<Duration>) -> std::io::Result<()> {
+    while !data.is_empty() {
+        let result = unsafe { libc::write(fd, data.as_ptr() as *const libc::c_void, data.len()) };
+
+        if result >= 0 {
+            data = &data[result as usize..];
+
+            continue;
+        }
+
+        let error = std::io::Error::last_os_error();
+
+        match error.kind() {
+            ErrorKind::Interrupted => {}
+            ErrorKind::WouldBlock => {
+                let mut fds = [libc::pollfd {
+                    fd,
+                    events: libc::POLLOUT,
+                    revents: 0,
+                }];
+
+                if poll_fds(&mut fds, timeout)? == 0 {
+                    return Err(std::io::Error::new(
+                        ErrorKind::TimedOut,
+                        "Peer has stopped reading",
+                    ));
+                }
+            }
+            _ => return Err(error),
+        }
+    }
+
+    Ok(())
+}
+
+fn shutdown_write(fd: RawFd) {
+    unsafe {
+        libc::shutdown(fd, libc::SHUT_WR);
+    }
+}
+
+fn set_nodelay(fd: RawFd) {
+    let enable: libc::c_int = 1;
+
+    /* Fails harmlessly on Unix-domain sockets */
+    unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_NODELAY,
+            &enable as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&enable) as libc::socklen_t,
+        );
+    }
+}
+
+struct ThreadedRelay {
+    client: OwnedFd,
+    server: OwnedFd,
+    policy: RelayPolicy,
+    config: AuxConfig,
+    handle: Handle,
+    hops: u64,
+    /* None until the server has sent anything */
+    tls: Option<bool>,
+    records: Vec<u8>,
+}
+
+impl ThreadedRelay {
+    fn upload_timeout(&self) -> Option<Duration> {
+        Some(SocketOptions::timeout(
+            self.config.socket_options.upload_idle_timeout,
+        ))
+        .filter(|timeout| *timeout != Duration::MAX)
+    }
+
+    fn download_timeout(&self) -> Option<Duration> {
+        Some(SocketOptions::timeout(
+            self.config.socket_options.download_idle_timeout,
+        ))
+        .filter(|timeout| *timeout != Duration::MAX)
+    }
+
+    /* Desync hooks need a tokio stream, which is borrowed on a duplicate
+     * descriptor for as long as the hops last or patterns have to apply
+     */
+    fn upload(&mut self, data: &[u8]) -> Result<()> {
+        let hooked = self.hops < self.config.desync_options.packet_hop
+            || !self.config.pattern_options.patterns.is_empty();
+
+        self.hops += 1;
+
+        if hooked {
+            let server = std::net::TcpStream::from(self.server.try_clone()?);
+
+            self.handle.block_on(async {
+                let mut server = TcpStream::from_std(server)?;
+
+                let transformed =
+                    client_hook(&mut server, data, self.policy.profile.as_deref()).await?;
+
+                server.write_all(&transformed).await?;
+
+                Ok::<(), anyhow::Error>(())
+            })?;
+        } else {
+            write_all_fd(self.server.as_raw_fd(), data, self.upload_timeout())?;
+        }
+
+        self.policy.shaper.throttle_blocking(data.len());
+
+        Ok(())
+    }
+
+    /* Coalesced TLS records are written out one by one, a partial record
+     * waits for the rest of it, just like the server has sent it
+     */
+    fn download(&mut self, data: &[u8]) -> Result<()> {
+        let client = self.client.as_raw_fd();
+        let timeout = self.download_timeout();
+
+        let tls = *self.tls.get_or_insert_with(|| looks_like_tls(data));
+
+        if !tls {
+            write_all_fd(client, data, timeout)?;
+        } else {
+            self.records.extend_from_slice(data);
+
+            while self.records.len() >= TLS_HEADER {
+                let length = u16::from_be_bytes([self.records[3], self.records[4]]) as usize;
+
+                if !looks_like_tls(&self.records) || length > MAX_TLS_RECORD {
+                    debug!("Server stream isn't TLS anymore, forwarding it as is");
+
+                    self.tls = Some(false);
+
+                    write_all_fd(client, &self.records, timeout)?;
+
+                    self.records.clear();
+
+                    break;
+                }
+
+                if self.records.len() < TLS_HEADER + length {
+                    break;
+                }
+
+                write_all_fd(client, &self.records[..TLS_HEADER + length], timeout)?;
+
+                self.records.drain(..TLS_HEADER + length);
+            }
+        }
+
+        self.policy.shaper.throttle_blocking(data.len());
+
+        Ok(())
+    }
+
+    fn run(mut self, leftover: Vec<u8>) -> Result<()> {
+        let client = self.client.as_raw_fd();
+        let server = self.server.as_raw_fd();
+
+        set_nodelay(client);
+        set_nodelay(server);
+
+        if !leftover.is_empty() {
+            self.upload(&leftover)?;
+        }
+
+        let mut buffer1: Vec<u8> = vec![0u8; self.config.socket_options.so_send_size];
+        let mut buffer2: Vec<u8> = vec![0u8; self.config.socket_options.so_recv_size];
+
+        let mut last_upload = Instant::now();
+        let mut last_download = Instant::now();
+
+        loop {
+            /* Same rule as the async relay: idle once both directions are */
+            let idle_deadline = match (self.upload_timeout(), self.download_timeout()) {
+                (Some(upload), Some(download)) => last_upload
+                    .checked_add(upload)
+                    .zip(last_download.checked_add(download))
+                    .map(|(upload, download)| upload.max(download)),
+                _ => None,
+            };
+
+            let mut fds = [
+                libc::pollfd {
+                    fd: client,
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: server,
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+            ];
+
+            let timeout =
+                idle_deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+
+            if poll_fds(&mut fds, timeout)? == 0 {
+                return Err(anyhow!("Connection has been idle for too long"));
+            }
+
+            let ready = |pollfd: &libc::pollfd| {
+                pollfd.revents & (libc::POLLIN | libc::POLLHUP | libc::POLLERR) != 0
+            };
+
+            if ready(&fds[0]) {
+                match read_fd(client, &mut buffer1) {
+                    Ok(0) => {
+                        shutdown_write(server);
+
+                        return Ok(());
+                    }
+                    Ok(n) => {
+                        self.upload(&buffer1[..n])?;
+
+                        last_upload = Instant::now();
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+
+            if ready(&fds[1]) {
+                match read_fd(server, &mut buffer2) {
+                    Ok(0) => {
+                        write_all_fd(client, &self.records, self.download_timeout())?;
+
+                        shutdown_write(client);
+
+                        return Ok(());
+                    }
+                    Ok(n) => {
+                        self.download(&buffer2[..n])?;
+
+                        last_download = Instant::now();
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+    }
+}
+
+/* The old multi-threaded approach: one OS thread per connection, where every
+ * read from the server becomes writes of its own instead of being batched
+ */
+pub async fn pipe_threaded(
+    client: OwnedFd,
+    leftover: Vec<u8>,
+    stream: TcpStream,
+    policy: RelayPolicy,
+) -> Result<()> {
+    let server: OwnedFd = stream.into_std()?.into();
+
+    let relay = ThreadedRelay {
+        client,
+        server,
+        policy,
+        config: parse_args(),
+        handle: Handle::current(),
+        hops: 0,
+        tls: None,
+        records: vec![],
+    };
+
+    let (done, result) = oneshot::channel();
+
+    debug!("Relaying the connection on a dedicated thread");
+
+    std::thread::Builder::new()
+        .name("wf-relay".to_string())
+        .spawn(move || {
+            let _ = done.send(relay.run(leftover));
+        })?;
+
+    result.await?
+}
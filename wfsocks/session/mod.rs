@@ -0,0 +1,56 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use log::{info, warn};
+
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static ACTIVE_SESSIONS: AtomicUsize = AtomicUsize::new(0);
+
+/* Counts a client session as in-flight until dropped */
+pub struct SessionGuard;
+
+impl SessionGuard {
+    pub fn begin() -> SessionGuard {
+        ACTIVE_SESSIONS.fetch_add(1, Ordering::SeqCst);
+
+        SessionGuard
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        ACTIVE_SESSIONS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub fn active_sessions() -> usize {
+    ACTIVE_SESSIONS.load(Ordering::SeqCst)
+}
+
+/* Waits for in-flight sessions to finish on their own, whatever is
+ * left after the deadline gets dropped together with the runtime
+ */
+pub async fn drain_sessions(deadline: Duration) {
+    let started = tokio::time::Instant::now();
+
+    info!(
+        "Draining {} active sessions for up to {deadline:?}",
+        active_sessions()
+    );
+
+    while active_sessions() != 0 {
+        if started.elapsed() >= deadline {
+            warn!(
+                "Drain deadline has passed, dropping {} sessions",
+                active_sessions()
+            );
+
+            return;
+        }
+
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+
+    info!("All sessions have been drained");
+}
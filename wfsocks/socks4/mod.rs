@@ -0,0 +1,240 @@
+use anyhow::{anyhow, Result};
+use log::{error, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use wfconfig::parse_args;
+use wfcore::router::{ClientIdentity, Router};
+
+use crate::handshake::within_handshake;
+use crate::listener::ClientStream;
+use crate::pipe::async_pipe::pipe_sockets;
+use crate::target::{connect_target, relay_after_hello, resolve_target, target_route};
+
+const CMD_CONNECT: u8 = 0x01;
+
+const REPLY_GRANTED: u8 = 0x5A;
+const REPLY_REJECTED: u8 = 0x5B;
+
+const MAX_FIELD_LEN: usize = 255;
+
+async fn read_null_terminated<S: ClientStream>(client: &mut S) -> Result<Vec<u8>> {
+    let mut field = vec![];
+
+    loop {
+        let byte = client.read_u8().await?;
+
+        if byte == 0 {
+            return Ok(field);
+        }
+
+        if field.len() == MAX_FIELD_LEN {
+            return Err(anyhow!("SOCKS4 field is longer than {MAX_FIELD_LEN} bytes"));
+        }
+
+        field.push(byte);
+    }
+}
+
+async fn send_reply<S: ClientStream>(
+    client: &mut S,
+    code: u8,
+    port: u16,
+    ip: [u8; 4],
+) -> Result<()> {
+    let mut packet = vec![0, code];
+
+    packet.extend_from_slice(&port.to_be_bytes());
+    packet.extend_from_slice(&ip);
+
+    client.write_all(&packet).await?;
+
+    Ok(())
+}
+
+struct Socks4Request {
+    command: u8,
+    port: u16,
+    ip: [u8; 4],
+    /* Only SOCKS4a requests carry a domain */
+    domain: Option<Vec<u8>>,
+}
+
+impl Socks4Request {
+    /* The same request in SOCKS5 wire format, which the resolver and the router consume */
+    fn socks5_request(&self) -> Vec<u8> {
+        let mut buffer = vec![5, self.command, 0];
+
+        match self.domain {
+            Some(ref domain) => {
+                buffer.push(3);
+                buffer.push(domain.len() as u8);
+                buffer.extend_from_slice(domain);
+            }
+            None => {
+                buffer.push(1);
+                buffer.extend_from_slice(&self.ip);
+            }
+        }
+
+        buffer.extend_from_slice(&self.port.to_be_bytes());
+
+        buffer
+    }
+}
+
+async fn read_request<S: ClientStream>(client: &mut S) -> Result<Socks4Request> {
+    let mut header = [0u8; 8];
+
+    client.read_exact(&mut header).await?;
+
+    if header[0] != 4 {
+        return Err(anyhow!("Unsupported SOCKS version {}", header[0]));
+    }
+
+    let _user_id = read_null_terminated(client).await?;
+
+    let ip = [header[4], header[5], header[6], header[7]];
+
+    /* SOCKS4a: 0.0.0.x with a non-zero x means that a domain follows the user id */
+    let is_socks4a = ip[..3] == [0, 0, 0] && ip[3] != 0;
+
+    let domain = if is_socks4a {
+        Some(read_null_terminated(client).await?)
+    } else {
+        None
+    };
+
+    Ok(Socks4Request {
+        command: header[1],
+        port: u16::from_be_bytes([header[2], header[3]]),
+        ip,
+        domain,
+    })
+}
+
+pub async fn socks4_proxy<S: ClientStream + 'static>(
+    mut client: S,
+    identity: ClientIdentity,
+) -> Result<()> {
+    let config = parse_args();
+
+    let request = within_handshake(read_request(&mut client)).await?;
+
+    let (port, ip) = (request.port, request.ip);
+
+    if !config.auth_options.users.is_empty() {
+        send_reply(&mut client, REPLY_REJECTED, port, ip).await?;
+
+        warn!("Rejecting a SOCKS4 client since SOCKS5 authentication is required");
+
+        return Err(anyhow!("SOCKS4 can't authenticate"));
+    }
+
+    if request.command != CMD_CONNECT {
+        send_reply(&mut client, REPLY_REJECTED, port, ip).await?;
+
+        return Err(anyhow!("Unsupported SOCKS4 command {}", request.command));
+    }
+
+    let buffer = request.socks5_request();
+
+    let parsed_data = resolve_target(config, &buffer, &identity).await?;
+
+    let route = target_route(&parsed_data, &identity);
+
+    if Router::waits_for_sni(&parse_args(), &route).await? {
+        send_reply(&mut client, REPLY_GRANTED, port, ip).await?;
+
+        return relay_after_hello(client, vec![], &parsed_data, route).await;
+    }
+
+    match connect_target(&parsed_data, route).await {
+        Ok((socket, policy)) => {
+            send_reply(&mut client, REPLY_GRANTED, port, ip).await?;
+
+            let _ = pipe_sockets(client, socket, policy).await;
+        }
+        Err(error) => {
+            error!("Connection aborted: {error} for a SOCKS4 request to port {port}");
+
+            send_reply(&mut client, REPLY_REJECTED, port, ip).await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn parse(request: &[u8]) -> Result<Socks4Request> {
+        block_on(read_request(&mut tokio::io::join(
+            request,
+            tokio::io::sink(),
+        )))
+    }
+
+    #[test]
+    fn reads_socks4_requests() {
+        let request = parse(b"\x04\x01\x01\xbb\x5d\xb8\xd8\x22user\x00").unwrap();
+
+        assert_eq!(request.command, CMD_CONNECT);
+        assert_eq!(request.port, 443);
+        assert_eq!(request.ip, [93, 184, 216, 34]);
+        assert_eq!(request.domain, None);
+
+        assert_eq!(
+            request.socks5_request(),
+            [5, 1, 0, 1, 93, 184, 216, 34, 1, 187]
+        );
+    }
+
+    #[test]
+    fn reads_socks4a_requests() {
+        let request = parse(b"\x04\x01\x00\x50\x00\x00\x00\x01\x00example.com\x00").unwrap();
+
+        assert_eq!(request.domain.as_deref(), Some(&b"example.com"[..]));
+
+        assert_eq!(
+            request.socks5_request(),
+            [&[5, 1, 0, 3, 11][..], b"example.com", &[0, 80]].concat()
+        );
+    }
+
+    #[test]
+    fn zero_address_is_not_socks4a() {
+        let request = parse(b"\x04\x01\x00\x50\x00\x00\x00\x00\x00").unwrap();
+
+        assert_eq!(request.domain, None);
+        assert_eq!(request.ip, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let long_user_id = [
+            &b"\x04\x01\x00\x50\x01\x02\x03\x04"[..],
+            &[b'a'; 256],
+            b"\x00",
+        ]
+        .concat();
+
+        let requests: [(&str, &[u8]); 5] = [
+            ("wrong version", b"\x05\x01\x00\x50\x01\x02\x03\x04\x00"),
+            ("short header", b"\x04\x01\x00\x50\x01"),
+            (
+                "unterminated user id",
+                b"\x04\x01\x00\x50\x01\x02\x03\x04user",
+            ),
+            (
+                "unterminated domain",
+                b"\x04\x01\x00\x50\x00\x00\x00\x01\x00example",
+            ),
+            ("overlong user id", &long_user_id),
+        ];
+
+        for (name, request) in requests {
+            assert!(parse(request).is_err(), "{name}");
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{error, info};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use wfacs5ch::client_hook;
+use wfcipu::parsers::ip::IpParser;
+use wfconfig::aux_config::AuxConfig;
+use wfconfig::NetworkProtocol;
+use wfcore::relay::RelayPolicy;
+use wfcore::router::{ClientIdentity, RouteContext, Router, RouterInterjectionStatus};
+use wfcore::socket::SocketOps;
+use wfdesync::utils::sni::Sni;
+
+use crate::listener::ClientStream;
+use crate::pipe::async_pipe::pipe_sockets;
+
+const FIRST_DATA_TIMEOUT: Duration = Duration::from_millis(500);
+const FIRST_DATA_SIZE: usize = 16 * 1024;
+
+/* Every frontend converts its request into the SOCKS5 wire format
+ * and resolves it here, so FakeDNS rules and DoH apply the same way
+ */
+pub async fn resolve_target(
+    config: AuxConfig,
+    buffer: &[u8],
+    identity: &ClientIdentity,
+) -> Result<IpParser> {
+    let router_responce = Router::interject_dns(config, buffer, identity).await?;
+
+    match router_responce {
+        RouterInterjectionStatus::Allow => wfdns::parser::parse(buffer).await,
+        RouterInterjectionStatus::AutoResolved(parsed) => Ok(parsed),
+    }
+}
+
+pub fn target_addr(parsed_data: &IpParser) -> Result<SocketAddr> {
+    let sock_addr = match parsed_data.host_raw.len() {
+        4 => {
+            let ip_bytes: [u8; 4] = unsafe { *(parsed_data.host_raw.as_ptr() as *const [u8; 4]) };
+
+            SocketAddr::new(ip_bytes.into(), parsed_data.port)
+        }
+        16 => {
+            let ip_bytes: [u8; 16] = unsafe { *(parsed_data.host_raw.as_ptr() as *const [u8; 16]) };
+
+            SocketAddr::new(ip_bytes.into(), parsed_data.port)
+        }
+        _ => return Err(anyhow!("No IP")),
+    };
+
+    if sock_addr.ip().is_unspecified() {
+        return Err(std::io::Error::new(
+            ErrorKind::HostUnreachable,
+            format!(
+                "Could not resolve {}",
+                String::from_utf8_lossy(&parsed_data.host_unprocessed)
+            ),
+        )
+        .into());
+    }
+
+    Ok(sock_addr)
+}
+
+pub fn target_route(parsed_data: &IpParser, identity: &ClientIdentity) -> RouteContext {
+    RouteContext {
+        domain: (parsed_data.dest_addr_type == 3)
+            .then(|| String::from_utf8_lossy(&parsed_data.host_unprocessed).to_string()),
+        protocol: Some(NetworkProtocol::TCP),
+        identity: identity.clone(),
+        ..Default::default()
+    }
+}
+
+pub async fn connect_target(
+    parsed_data: &IpParser,
+    route: RouteContext,
+) -> Result<(TcpStream, RelayPolicy)> {
+    /* An unresolved domain may still be reachable through an upstream
+     * doing remote DNS, so leave the final word to the router
+     */
+    let sock_addr = match target_addr(parsed_data) {
+        Err(_) if parsed_data.dest_addr_type == 3 => {
+            SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), parsed_data.port)
+        }
+        result => result?,
+    };
+
+    let mut addrs: Vec<SocketAddr> = parsed_data
+        .resolved
+        .iter()
+        .map(|ip| SocketAddr::new(*ip, parsed_data.port))
+        .collect();
+
+    if addrs.is_empty() {
+        addrs.push(sock_addr);
+    }
+
+    SocketOps::connect_socket(route, &addrs).await
+}
+
+/* Whether the data starts a TLS record that hasn't fully arrived yet */
+pub fn record_incomplete(data: &[u8], limit: usize) -> bool {
+    match data {
+        [0x16, _, _, high, low, ..] => {
+            data.len() < (5 + u16::from_be_bytes([*high, *low]) as usize).min(limit)
+        }
+        [0x16, ..] => true,
+        _ => false,
+    }
+}
+
+/* Reads on top of what's already there until a ClientHello split over
+ * several segments is complete. Server-first protocols won't send
+ * anything, so don't wait for too long
+ */
+pub async fn read_first_data<S: AsyncRead + Unpin>(
+    client: &mut S,
+    mut first_data: Vec<u8>,
+) -> Result<Vec<u8>> {
+    if !first_data.is_empty() && !record_incomplete(&first_data, FIRST_DATA_SIZE) {
+        return Ok(first_data);
+    }
+
+    let deadline = Instant::now() + FIRST_DATA_TIMEOUT;
+
+    let mut chunk = vec![0u8; FIRST_DATA_SIZE];
+
+    loop {
+        let n = match tokio::time::timeout_at(deadline, client.read(&mut chunk)).await {
+            Ok(read) => read?,
+            Err(_) => break,
+        };
+
+        first_data.extend_from_slice(&chunk[..n]);
+
+        if n == 0 || !record_incomplete(&first_data, FIRST_DATA_SIZE) {
+            break;
+        }
+    }
+
+    Ok(first_data)
+}
+
+/* Once the client is told that it's connected, it sends the ClientHello
+ * and SNI rules get their say before anything is dialed
+ */
+pub async fn relay_after_hello<S: ClientStream + 'static>(
+    mut client: S,
+    leftover: Vec<u8>,
+    parsed_data: &IpParser,
+    route: RouteContext,
+) -> Result<()> {
+    let first_data = read_first_data(&mut client, leftover).await?;
+
+    let route = RouteContext {
+        sni: extract_sni(&first_data),
+        ..route
+    };
+
+    let (mut socket, policy) = match connect_target(parsed_data, route).await {
+        Ok(connected) => connected,
+        Err(error) => {
+            /* Too late for a proper reply, closing is all that's left */
+            error!(
+                "Connection aborted: {error} with a host {}",
+                String::from_utf8_lossy(&parsed_data.host_unprocessed)
+            );
+
+            return Ok(());
+        }
+    };
+
+    send_initial_data(&mut socket, &first_data, &policy).await?;
+
+    let _ = pipe_sockets(client, socket, policy).await;
+
+    Ok(())
+}
+
+pub fn extract_sni(data: &[u8]) -> Option<String> {
+    let (start, end) = Sni::parse_sni_index(data.to_vec());
+
+    if (start, end) == (0, 0) {
+        return None;
+    }
+
+    Some(String::from_utf8_lossy(&data[start as usize..end as usize]).to_string())
+}
+
+/* Transparent frontends already know the destination address, the SNI is
+ * only used to let FakeDNS rules override it
+ */
+pub async fn reroute_by_sni(
+    config: AuxConfig,
+    sni: &str,
+    original: SocketAddr,
+    identity: &ClientIdentity,
+) -> Result<SocketAddr> {
+    if sni.len() > 255 {
+        return Ok(original);
+    }
+
+    let mut buffer = vec![5, 1, 0, 3, sni.len() as u8];
+
+    buffer.extend_from_slice(sni.as_bytes());
+    buffer.extend_from_slice(&original.port().to_be_bytes());
+
+    match Router::interject_dns(config, &buffer, identity).await? {
+        RouterInterjectionStatus::AutoResolved(parsed) => {
+            let sock_addr = SocketAddr::new(target_addr(&parsed)?.ip(), original.port());
+
+            info!("{sni} has been rerouted from {original} to {sock_addr}");
+
+            Ok(sock_addr)
+        }
+        RouterInterjectionStatus::Allow => Ok(original),
+    }
+}
+
+/* The SNI stands in for the domain of transparent connections, as it's the
+ * only name they ever have
+ */
+pub fn sni_route(sni: Option<String>, identity: &ClientIdentity) -> RouteContext {
+    RouteContext {
+        domain: sni.clone(),
+        sni,
+        protocol: Some(NetworkProtocol::TCP),
+        identity: identity.clone(),
+        ..Default::default()
+    }
+}
+
+pub async fn send_initial_data(
+    socket: &mut TcpStream,
+    data: &[u8],
+    policy: &RelayPolicy,
+) -> Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+
+    let transformed = client_hook(socket, data, policy.profile.as_deref()).await?;
+
+    socket.write_all(&transformed).await?;
+
+    Ok(())
+}
@@ -0,0 +1,176 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{debug, error};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+use wfconfig::aux_config::BindMode;
+use wfconfig::parse_args;
+use wfcore::router::ClientIdentity;
+use wfcore::socket::SocketOps;
+
+use crate::pipe::async_pipe::pipe_sockets;
+use crate::target::{extract_sni, record_incomplete, reroute_by_sni, sni_route};
+
+const SNI_PEEK_TIMEOUT: Duration = Duration::from_millis(500);
+const SNI_PEEK_SIZE: usize = 16 * 1024;
+const SNI_PEEK_INTERVAL: Duration = Duration::from_millis(10);
+
+#[cfg(target_os = "linux")]
+fn original_dst(client: &TcpStream) -> Result<SocketAddr> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = client.as_raw_fd();
+
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+    let (level, name) = match client.local_addr()? {
+        SocketAddr::V4(_) => (libc::SOL_IP, libc::SO_ORIGINAL_DST),
+        SocketAddr::V6(_) => (libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST),
+    };
+
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut storage as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if result != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { *(&storage as *const _ as *const libc::sockaddr_in) };
+
+            Ok(SocketAddr::new(
+                IpAddr::from(u32::from_be(addr.sin_addr.s_addr).to_be_bytes()),
+                u16::from_be(addr.sin_port),
+            ))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { *(&storage as *const _ as *const libc::sockaddr_in6) };
+
+            Ok(SocketAddr::new(
+                IpAddr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+            ))
+        }
+        family => Err(anyhow!("Unknown original destination family {family}")),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn original_dst(_client: &TcpStream) -> Result<SocketAddr> {
+    Err(anyhow!("REDIRECT is only available on Linux"))
+}
+
+#[cfg(target_os = "linux")]
+pub fn bind_tproxy_listener(addr: SocketAddr) -> Result<TcpListener> {
+    use std::os::unix::io::AsRawFd;
+    use tokio::net::TcpSocket;
+
+    let (socket, level, name) = match addr {
+        SocketAddr::V4(_) => (TcpSocket::new_v4()?, libc::SOL_IP, libc::IP_TRANSPARENT),
+        SocketAddr::V6(_) => (TcpSocket::new_v6()?, libc::SOL_IPV6, libc::IPV6_TRANSPARENT),
+    };
+
+    let enable: libc::c_int = 1;
+
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &enable as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&enable) as libc::socklen_t,
+        )
+    };
+
+    if result != 0 {
+        return Err(anyhow!(
+            "Failed to enable IP_TRANSPARENT (CAP_NET_ADMIN is required): {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+
+    Ok(socket.listen(1024)?)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind_tproxy_listener(_addr: SocketAddr) -> Result<TcpListener> {
+    Err(anyhow!("TPROXY is only available on Linux"))
+}
+
+async fn peek_sni(client: &TcpStream) -> Option<String> {
+    let mut buffer = vec![0u8; SNI_PEEK_SIZE];
+
+    /* Server-first protocols won't send anything, so don't wait for too long */
+    let deadline = Instant::now() + SNI_PEEK_TIMEOUT;
+
+    loop {
+        let n = tokio::time::timeout_at(deadline, client.peek(&mut buffer))
+            .await
+            .ok()?
+            .ok()?;
+
+        /* A ClientHello split over several segments only has its SNI once
+         * the whole record is in
+         */
+        if !record_incomplete(&buffer[..n], SNI_PEEK_SIZE) || Instant::now() >= deadline {
+            return extract_sni(&buffer[..n]);
+        }
+
+        /* Peeking returns the same bytes right away, so give the rest time to arrive */
+        tokio::time::sleep_until(deadline.min(Instant::now() + SNI_PEEK_INTERVAL)).await;
+    }
+}
+
+pub async fn transparent_proxy(
+    client: TcpStream,
+    mode: BindMode,
+    identity: ClientIdentity,
+) -> Result<()> {
+    let original = match mode {
+        BindMode::Redirect => original_dst(&client)?,
+        BindMode::Tproxy => client.local_addr()?,
+        BindMode::Proxy | BindMode::Tun => return Err(anyhow!("Not a transparent listener")),
+    };
+
+    if original == client.local_addr()? && mode == BindMode::Redirect {
+        return Err(anyhow!(
+            "Connection to {original} wasn't redirected, refusing to loop"
+        ));
+    }
+
+    let sni = peek_sni(&client).await;
+
+    debug!("Transparent connection to {original} with SNI {sni:?}");
+
+    let config = parse_args();
+
+    let sock_addr = match sni {
+        Some(ref sni) => reroute_by_sni(config, sni, original, &identity).await?,
+        None => original,
+    };
+
+    match SocketOps::connect_socket(sni_route(sni, &identity), &[sock_addr]).await {
+        Ok((socket, policy)) => {
+            let _ = pipe_sockets(client, socket, policy).await;
+        }
+        Err(error) => {
+            error!("Connection aborted: {error} with an address {sock_addr}");
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,263 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use ipnetwork::IpNetwork;
+use ipstack::{IpStack, IpStackConfig, IpStackStream, IpStackTcpStream, IpStackUdpStream};
+use log::{debug, info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use wfconfig::aux_config::{AuxConfig, BindOptions};
+use wfconfig::{parse_args, NetworkProtocol};
+use wfcore::router::{ClientIdentity, RouteAction, RouteContext, Router};
+use wfcore::socket::{BlockedByRouter, SocketOps};
+
+use crate::pipe::async_pipe::pipe_sockets;
+use crate::session::SessionGuard;
+use crate::target::{extract_sni, read_first_data, reroute_by_sni, send_initial_data, sni_route};
+
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/* The tun crate only knows how to assign IPv4 addresses, so the IPv6 one
+ * goes through the same ioctl `ip -6 addr add` ends up with
+ */
+fn add_ipv6_address(options: &BindOptions) -> Result<()> {
+    use std::ffi::CString;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    let network: IpNetwork = options.bind_iface_ipv6.parse()?;
+
+    let IpNetwork::V6(network) = network else {
+        return Err(anyhow!("iface-ipv6-ip {network} is not an IPv6 network"));
+    };
+
+    let name = CString::new(options.bind_tun_name.as_str())?;
+
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+
+    if index == 0 {
+        return Err(anyhow!(
+            "No interface named {}: {}",
+            options.bind_tun_name,
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    let fd = unsafe { libc::socket(libc::AF_INET6, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let request = libc::in6_ifreq {
+        ifr6_addr: libc::in6_addr {
+            s6_addr: network.ip().octets(),
+        },
+        ifr6_prefixlen: network.prefix() as u32,
+        ifr6_ifindex: index as libc::c_int,
+    };
+
+    let result = unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCSIFADDR as _, &request) };
+
+    if result != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+fn create_device(options: &BindOptions) -> Result<tun::AsyncDevice> {
+    let network: IpNetwork = options.bind_iface_ipv4.parse()?;
+
+    let IpNetwork::V4(network) = network else {
+        return Err(anyhow!("iface-ipv4-ip {network} is not an IPv4 network"));
+    };
+
+    if network.ip().is_unspecified() {
+        return Err(anyhow!(
+            "Set iface-ipv4-ip to the TUN address, for example 10.13.37.1/24"
+        ));
+    }
+
+    let mut tun_config = tun::Configuration::default();
+
+    tun_config
+        .tun_name(&options.bind_tun_name)
+        .address(network.ip())
+        .netmask(network.mask())
+        .mtu(u16::try_from(options.bind_iface_mtu)?)
+        .up();
+
+    let device = tun::create_as_async(&tun_config)?;
+
+    if options.bind_iface_ipv6 != "::" {
+        add_ipv6_address(options).map_err(|error| {
+            anyhow!(
+                "Failed to assign {} to the TUN device: {error}",
+                options.bind_iface_ipv6
+            )
+        })?;
+    }
+
+    Ok(device)
+}
+
+async fn handle_tcp(mut client: IpStackTcpStream) -> Result<()> {
+    let original = client.peer_addr();
+
+    let first_data = read_first_data(&mut client, vec![]).await?;
+
+    let sni = extract_sni(&first_data);
+
+    debug!("TUN connection to {original} with SNI {sni:?}");
+
+    let identity = ClientIdentity::default();
+
+    let sock_addr = match sni {
+        Some(ref sni) => reroute_by_sni(parse_args(), sni, original, &identity).await?,
+        None => original,
+    };
+
+    let (mut socket, policy) =
+        SocketOps::connect_socket(sni_route(sni, &identity), &[sock_addr]).await?;
+
+    send_initial_data(&mut socket, &first_data, &policy).await?;
+
+    pipe_sockets(client, socket, policy).await
+}
+
+/* UDP has no upstream support, so a flow routed anywhere but direct is dropped */
+async fn route_udp(original: SocketAddr) -> Result<SocketAddr> {
+    let route = RouteContext {
+        ip: Some(original.ip()),
+        port: Some(original.port()),
+        protocol: Some(NetworkProtocol::UDP),
+        identity: ClientIdentity::default(),
+        ..Default::default()
+    };
+
+    let decision = Router::route(&parse_args(), &route).await?;
+
+    match decision.action {
+        RouteAction::Direct => {}
+        RouteAction::Block => return Err(BlockedByRouter.into()),
+        action => return Err(anyhow!("UDP can't be forwarded with {action:?}")),
+    }
+
+    Ok(match decision.address {
+        Some(address) => SocketAddr::new(address, original.port()),
+        None => original,
+    })
+}
+
+async fn handle_udp(mut client: IpStackUdpStream) -> Result<()> {
+    let dest = route_udp(client.peer_addr()).await?;
+
+    let (bind_addr, device_name) = match dest {
+        SocketAddr::V4(_) => ("0.0.0.0:0", parse_args().bind_options.iface_ipv4),
+        SocketAddr::V6(_) => ("[::]:0", parse_args().bind_options.iface_ipv6),
+    };
+
+    let upstream = UdpSocket::bind(bind_addr).await?;
+
+    if device_name != "default" {
+        upstream.bind_device(Some(device_name.as_bytes()))?;
+    }
+
+    upstream.connect(dest).await?;
+
+    let mut client_buffer = vec![0u8; 65535];
+    let mut upstream_buffer = vec![0u8; 65535];
+
+    loop {
+        tokio::select! {
+            read = client.read(&mut client_buffer) => {
+                let n = read?;
+
+                if n == 0 {
+                    break;
+                }
+
+                upstream.send(&client_buffer[..n]).await?;
+            }
+            recv = upstream.recv(&mut upstream_buffer) => {
+                let n = recv?;
+
+                client.write_all(&upstream_buffer[..n]).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn tun_proxy(config: AuxConfig) -> Result<()> {
+    let options = &config.bind_options;
+
+    let device = create_device(options)?;
+
+    let mut ipstack_config = IpStackConfig::default();
+
+    ipstack_config
+        .mtu(u16::try_from(options.bind_iface_mtu)?)?
+        .udp_timeout(UDP_IDLE_TIMEOUT);
+
+    let mut stack = IpStack::new(ipstack_config, device);
+
+    info!(
+        "TUN device {} is up with {} (MTU {})",
+        options.bind_tun_name, options.bind_iface_ipv4, options.bind_iface_mtu
+    );
+
+    for (name, iface) in [
+        ("iface-ipv4", &options.iface_ipv4),
+        ("iface-ipv6", &options.iface_ipv6),
+    ] {
+        if iface == "default" || iface == &options.bind_tun_name {
+            warn!("{name} is {iface}, make sure that Waterfall's own connections aren't routed back into the TUN device");
+        }
+    }
+
+    loop {
+        match stack.accept().await? {
+            IpStackStream::Tcp(tcp) => {
+                let session = SessionGuard::begin();
+
+                tokio::spawn(async move {
+                    let _session = session;
+
+                    let peer = tcp.peer_addr();
+
+                    if let Err(error) = handle_tcp(tcp).await {
+                        debug!("TUN TCP flow to {peer} has ended: {error}");
+                    }
+                });
+            }
+            IpStackStream::Udp(udp) => {
+                let session = SessionGuard::begin();
+
+                tokio::spawn(async move {
+                    let _session = session;
+
+                    let peer = udp.peer_addr();
+
+                    if let Err(error) = handle_udp(udp).await {
+                        debug!("TUN UDP flow to {peer} has ended: {error}");
+                    }
+                });
+            }
+            IpStackStream::UnknownTransport(packet) => {
+                debug!(
+                    "Dropping an unsupported {:?} packet to {}",
+                    packet.ip_protocol(),
+                    packet.dst_addr()
+                );
+            }
+            IpStackStream::UnknownNetwork(packet) => {
+                debug!("Dropping an unknown {} bytes packet", packet.len());
+            }
+        }
+    }
+}